@@ -0,0 +1,245 @@
+//! # Controlling File Access During Evaluation
+//!
+//! An [`AccessFilter`] calls a Rust function for each file or directory that evaluated code
+//! reads through `import`, `readFile`, `readDir` or `pathExists`, which can allow the access,
+//! deny it with an error, or redirect it to another path.
+//!
+//! This is not a sandbox: code can bypass the filter, as described below. Isolating untrusted
+//! code requires the `restrict-eval` or `pure-eval` settings; see [`EvalStateBuilder`][`crate::eval_state::EvalStateBuilder`].
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::access::{AccessFilter, Decision};
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+//! let filter = AccessFilter::new(&mut es, |_access, path| {
+//!     if path.starts_with("/etc") {
+//!         Decision::Deny("tenants may not read /etc".to_string())
+//!     } else {
+//!         Decision::Allow
+//!     }
+//! })?;
+//! let err = filter
+//!     .eval_from_string(&mut es, "builtins.readFile /etc/passwd", "<example>")
+//!     .unwrap_err();
+//! assert!(err.to_string().contains("tenants may not read /etc"));
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## How it works
+//!
+//! The filter evaluates code with [`ScopedOverrides`], overriding `import`, `scopedImport`,
+//! `readFile`, `readDir` and `pathExists`. See [`ScopedOverrides`] for the limitations; in
+//! particular, other builtins such as `builtins.path`, and the original functions, such as
+//! `__readFile`, are not filtered.
+
+use crate::eval_state::EvalState;
+use crate::primop::{PrimOp, PrimOpMeta};
+use crate::scoped::ScopedOverrides;
+use crate::value::Value;
+use anyhow::{bail, Result};
+use cstr::cstr;
+use std::ops::Deref;
+
+/// How evaluation wants to access a path.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Access {
+    /// `import` or `scopedImport`, including the file passed to [`ScopedOverrides::import`].
+    Import,
+    /// `builtins.readFile`.
+    ReadFile,
+    /// `builtins.readDir`.
+    ReadDir,
+    /// `builtins.pathExists`.
+    PathExists,
+}
+
+/// What an [`AccessFilter`] does with an access.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Decision {
+    /// Access the path as requested.
+    Allow,
+    /// Fail the evaluation with an error that contains the path and this reason.
+    ///
+    /// The error can not be caught with `builtins.tryEval`.
+    Deny(String),
+    /// Access this absolute path instead.
+    ///
+    /// Relative paths in files that are imported this way are resolved against the new path.
+    Redirect(String),
+}
+
+impl Access {
+    /// Parses the `kind` that the hooks of this module and of [`ifd`][`crate::ifd`] pass to their primop.
+    pub(crate) fn from_kind(kind: &str) -> Result<Access> {
+        Ok(match kind {
+            "import" => Access::Import,
+            "readFile" => Access::ReadFile,
+            "readDir" => Access::ReadDir,
+            "pathExists" => Access::PathExists,
+            _ => bail!("unknown access kind `{}`", kind),
+        })
+    }
+}
+
+impl Decision {
+    /// Returns the value for the primop to return: an empty string to allow, or the path to access instead.
+    ///
+    /// `subject` describes the access in errors, e.g. `access to path '/etc/passwd'`.
+    pub(crate) fn into_value(self, es: &mut EvalState, subject: &str) -> Result<Value> {
+        match self {
+            Decision::Allow => es.new_value_str(""),
+            Decision::Deny(reason) => bail!("{} was denied: {}", subject, reason),
+            Decision::Redirect(target) => {
+                if !target.starts_with('/') {
+                    bail!(
+                        "{} was redirected to `{}`, which is not an absolute path",
+                        subject,
+                        target
+                    );
+                }
+                es.new_value_str(&target)
+            }
+        }
+    }
+}
+
+/// The hooks for [`ScopedOverrides`], given the checking primop.
+///
+/// `check` returns an empty string to allow an access, or the path to redirect it to.
+const HOOKS_EXPR: &str = r#"
+check:
+let
+  checked = kind: p:
+    let redirect = check kind (toString p);
+    in if redirect == "" then p else /. + redirect;
+in
+{
+  importPath = checked "import";
+  builtins = {
+    readFile = p: builtins.readFile (checked "readFile" p);
+    readDir = p: builtins.readDir (checked "readDir" p);
+    pathExists = p: builtins.pathExists (checked "pathExists" p);
+  };
+}
+"#;
+
+/// Decides on the file accesses of the code that it evaluates.
+///
+/// See the [module documentation](self) for an example and limitations.
+pub struct AccessFilter {
+    overrides: ScopedOverrides,
+}
+
+impl AccessFilter {
+    /// Creates a filter for evaluating with `eval_state`, which calls `decide` with each access and the absolute path.
+    pub fn new(
+        eval_state: &mut EvalState,
+        decide: impl Fn(Access, &str) -> Decision + 'static,
+    ) -> Result<Self> {
+        let primop = PrimOp::new(
+            eval_state,
+            PrimOpMeta {
+                name: cstr!("checkAccess"),
+                doc: cstr!("Checks whether a path may be accessed, and returns the path to access instead, or an empty string."),
+                args: [cstr!("kind"), cstr!("path")],
+            },
+            Box::new(move |es, [kind, path]| {
+                let access = Access::from_kind(&es.require_string(kind)?)?;
+                let path = es.require_string(path)?;
+                decide(access, &path).into_value(es, &format!("access to path '{}'", path))
+            }),
+        )?;
+        let overrides = ScopedOverrides::new(eval_state, HOOKS_EXPR, primop)?;
+        Ok(AccessFilter { overrides })
+    }
+}
+
+/// Provides [`ScopedOverrides::import`] and [`ScopedOverrides::eval_from_string`] for evaluating through the filter.
+impl Deref for AccessFilter {
+    type Target = ScopedOverrides;
+
+    fn deref(&self) -> &ScopedOverrides {
+        &self.overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use nix_bindings_store::store::Store;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn access_allow_deny_redirect() {
+        gc_registering_current_thread(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let d = dir.path().to_str().unwrap().to_string();
+            std::fs::write(
+                dir.path().join("default.nix"),
+                "{ data = builtins.readFile ./data.txt; secret = builtins.readFile ./secret.txt; }",
+            )
+            .unwrap();
+            std::fs::write(dir.path().join("data.txt"), "data").unwrap();
+            std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+            std::fs::write(dir.path().join("replacement.txt"), "replacement").unwrap();
+
+            let accesses = Rc::new(RefCell::new(Vec::new()));
+            let recorded = accesses.clone();
+            let dir_path = d.clone();
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let filter = AccessFilter::new(&mut es, move |access, path| {
+                recorded.borrow_mut().push((access, path.to_string()));
+                if path.ends_with("secret.txt") {
+                    Decision::Deny("secrets are off limits".to_string())
+                } else if path.ends_with("data.txt") {
+                    Decision::Redirect(format!("{dir_path}/replacement.txt"))
+                } else {
+                    Decision::Allow
+                }
+            })
+            .unwrap();
+
+            let v = filter.import(&mut es, &format!("{d}/default.nix")).unwrap();
+            let data = es.require_attrs_select(&v, "data").unwrap();
+            assert_eq!(es.require_string(&data).unwrap(), "replacement");
+            let err = es.require_attrs_select(&v, "secret").unwrap_err();
+            let err = err.to_string();
+            assert!(err.contains("secrets are off limits"), "{}", err);
+            assert!(err.contains(&format!("{d}/secret.txt")), "{}", err);
+            assert_eq!(
+                *accesses.borrow(),
+                vec![
+                    (Access::Import, format!("{d}/default.nix")),
+                    (Access::ReadFile, format!("{d}/data.txt")),
+                    (Access::ReadFile, format!("{d}/secret.txt")),
+                ]
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn access_redirect_must_be_absolute() {
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let filter =
+                AccessFilter::new(&mut es, |_, _| Decision::Redirect("relative".to_string()))
+                    .unwrap();
+            let err = filter
+                .eval_from_string(&mut es, "builtins.pathExists /tmp", "<test>")
+                .unwrap_err();
+            assert!(err.to_string().contains("not an absolute path"));
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+//! # Async Evaluation with Tokio
+//!
+//! Requires the `tokio` feature.
+//!
+//! Nix operations block the calling thread, which must not happen on an async
+//! runtime's worker threads. [`AsyncEvalStatePool`] runs operations on the GC-registered
+//! worker threads of an [`EvalStatePool`] instead, and returns futures for their results.
+//!
+//! ```rust
+//! # use nix_bindings_expr::async_eval::AsyncEvalStatePool;
+//! # use nix_bindings_expr::eval_state::{EvalStateBuilder, test_init};
+//! # use nix_bindings_expr::pool::EvalStatePool;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # async fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! let pool = AsyncEvalStatePool::new(EvalStatePool::new(2, 8, || {
+//!     EvalStateBuilder::new(Store::open(None, HashMap::new())?)
+//! })?);
+//! let n = pool
+//!     .run(|es| {
+//!         let v = es.eval_from_string("1 + 2", "<example>")?;
+//!         es.require_int(&v)
+//!     })
+//!     .await?;
+//! assert_eq!(n, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Scope
+//!
+//! There is no async wrapper for locking flakes, because `nix-bindings-flake` depends on
+//! this crate. Other blocking operations that need an [`EvalState`], such as
+//! `nix_bindings_flake::LockedFlake::lock`, can be run with [`run`][`AsyncEvalStatePool::run`],
+//! by creating their inputs inside the closure.
+//!
+//! ## Cancellation
+//!
+//! An operation whose future is dropped stays in the queue, but is skipped when a worker
+//! picks it up. Interrupting an operation that is already running is not supported, as
+//! the Nix C API does not offer a way to do so; such an operation runs to completion on
+//! its worker thread, and its result is discarded.
+
+use crate::eval_state::EvalState;
+use crate::pool::{EvalJob, EvalStatePool};
+use anyhow::{format_err, Result};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+/// An async interface to an [`EvalStatePool`].
+///
+/// Cloning is cheap and refers to the same pool.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone)]
+pub struct AsyncEvalStatePool {
+    pool: Arc<EvalStatePool>,
+}
+
+impl AsyncEvalStatePool {
+    /// Wraps `pool` for use from async code.
+    pub fn new(pool: EvalStatePool) -> Self {
+        AsyncEvalStatePool {
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// Runs `f` on one of the pool's [`EvalState`]s, and returns its result.
+    ///
+    /// See the [module documentation](self#cancellation) for what happens when the returned future is dropped.
+    #[doc(alias = "spawn")]
+    #[doc(alias = "spawn_blocking")]
+    pub async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut EvalState) -> Result<R> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let pool = self.pool.clone();
+        // Queueing blocks while the queue is full, so do it off the runtime threads.
+        tokio::task::spawn_blocking(move || {
+            pool.submit_task(Box::new(move |es| {
+                // The future was dropped before the task started; skip it.
+                if sender.is_closed() {
+                    return;
+                }
+                let _ = sender.send(f(es));
+            }))
+        })
+        .await
+        .map_err(|e| format_err!("AsyncEvalStatePool: could not queue task: {}", e))??;
+        receiver
+            .await
+            .unwrap_or_else(|_| Err(format_err!("evaluation job panicked")))
+    }
+
+    /// Evaluates an [`EvalJob`], like [`EvalStatePool::submit`].
+    pub async fn evaluate(&self, job: EvalJob) -> Result<String> {
+        self.run(move |es| job.run(es)).await
+    }
+
+    /// Builds the derivation at `drv_path`, like [`Store::realise`][`nix_bindings_store::store::Store::realise`],
+    /// and returns the store paths of its outputs by output name.
+    ///
+    /// This occupies one of the pool's workers for the duration of the build.
+    #[cfg(nix_at_least = "2.33.0pre")]
+    pub async fn realise(
+        &self,
+        drv_path: String,
+    ) -> Result<std::collections::BTreeMap<String, String>> {
+        self.run(move |es| {
+            let mut store = es.store().clone();
+            let path = store.parse_store_path(&drv_path)?;
+            store
+                .realise(&path)?
+                .into_iter()
+                .map(|(name, out)| Ok((name, store.real_path(&out)?)))
+                .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::EvalStateBuilder;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+    use std::io::Write as _;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn new_pool(threads: usize) -> AsyncEvalStatePool {
+        AsyncEvalStatePool::new(
+            EvalStatePool::new(threads, 4, || {
+                EvalStateBuilder::new(Store::open(None, HashMap::new())?)
+            })
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn async_run_and_evaluate() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "{{ a.b = [ true ]; }}").unwrap();
+        let pool = new_pool(2);
+        rt.block_on(async {
+            let (x, json) = tokio::join!(
+                pool.run(|es| {
+                    let v = es.eval_from_string("builtins.length [ 1 2 3 ]", "<test>")?;
+                    es.require_int(&v)
+                }),
+                pool.evaluate(EvalJob::new(file.path(), ["a", "b"]))
+            );
+            assert_eq!(x.unwrap(), 3);
+            assert_eq!(json.unwrap(), "[true]");
+            let err = pool
+                .run(|es| {
+                    es.eval_from_string("throw \"async oops\"", "<test>")
+                        .map(|_| ())
+                })
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("async oops"));
+        });
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.33.0pre")]
+    fn async_realise() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pool = new_pool(1);
+        rt.block_on(async {
+            let drv_path = pool
+                .run(|es| {
+                    let v = es.eval_from_string(
+                        r#"
+                        (derivation {
+                            name = "letsbuild";
+                            system = builtins.currentSystem;
+                            builder = "/bin/sh";
+                            args = [ "-c" "echo foo > $out" ];
+                        }).drvPath
+                        "#,
+                        "<test>",
+                    )?;
+                    es.require_string(&v)
+                })
+                .await
+                .unwrap();
+            let outputs = pool.realise(drv_path).await.unwrap();
+            assert_eq!(outputs.keys().collect::<Vec<_>>(), ["out"]);
+            assert!(outputs["out"].ends_with("-letsbuild"));
+            assert_eq!(std::fs::read_to_string(&outputs["out"]).unwrap(), "foo\n");
+        });
+    }
+
+    /// Sends on its channel when dropped.
+    struct DropSignal(std::sync::mpsc::Sender<()>);
+
+    impl Drop for DropSignal {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[test]
+    fn async_dropped_future_skips_job() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pool = new_pool(1);
+        let ran = Arc::new(AtomicBool::new(false));
+        let (dropped_tx, dropped_rx) = std::sync::mpsc::channel();
+        rt.block_on(async {
+            // Keep the only worker busy until released.
+            let (started_tx, started_rx) = oneshot::channel();
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            let busy = {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    pool.run(move |_| {
+                        let _ = started_tx.send(());
+                        let _ = release_rx.recv();
+                        Ok(())
+                    })
+                    .await
+                })
+            };
+            started_rx.await.unwrap();
+
+            // Start queueing a job, then drop its future. Queueing continues in the
+            // background, and the worker can't pick the job up before it is released.
+            {
+                let ran = ran.clone();
+                let signal = DropSignal(dropped_tx);
+                let skipped = pool.run(move |_| {
+                    let _signal = signal;
+                    ran.store(true, Ordering::SeqCst);
+                    Ok(())
+                });
+                tokio::pin!(skipped);
+                tokio::select! {
+                    biased;
+                    _ = &mut skipped => panic!("job ran while the worker was busy"),
+                    _ = std::future::ready(()) => {}
+                }
+            }
+
+            release_tx.send(()).unwrap();
+            busy.await.unwrap().unwrap();
+        });
+        // The job's closure is dropped when it is skipped, or after it ran.
+        dropped_rx.recv().unwrap();
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+}
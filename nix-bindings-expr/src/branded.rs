@@ -0,0 +1,343 @@
+//! # Branded Values
+//!
+//! A [`Value`] does not know which [`EvalState`] it was created by, so nothing
+//! stops a program from passing it to a different `EvalState`, which the Nix
+//! evaluator does not support.
+//!
+//! This module provides a wrapper that attaches a _brand_ to the `EvalState` and
+//! to every value it returns. A brand is a unique lifetime that is created by
+//! [`EvalState::with_brand`] and only exists inside its closure. Values with
+//! different brands have different types, so mixing them is a compile error, and
+//! branded values can not outlive the closure.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let store = Store::open(None, HashMap::new())?;
+//! let mut es = EvalState::new(store, [])?;
+//! let n = es.with_brand(|mut es| -> anyhow::Result<_> {
+//!     let f = es.eval_from_string("x: x + 1", "<example>")?;
+//!     let x = es.new_value_int(41)?;
+//!     let y = es.call(&f, &x)?;
+//!     es.require_int(&y)
+//! })?;
+//! assert_eq!(n, 42);
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Using a value from another brand does not compile:
+//!
+//! ```compile_fail
+//! # use nix_bindings_expr::eval_state::EvalState;
+//! fn mix(a: &mut EvalState, b: &mut EvalState) {
+//!     a.with_brand(|mut a| {
+//!         let v = a.new_value_int(1).unwrap();
+//!         b.with_brand(|mut b| {
+//!             b.require_int(&v).unwrap();
+//!         });
+//!     });
+//! }
+//! ```
+//!
+//! Neither does returning a branded value from the closure:
+//!
+//! ```compile_fail
+//! # use nix_bindings_expr::eval_state::EvalState;
+//! fn escape(es: &mut EvalState) {
+//!     let v = es.with_brand(|mut es| es.new_value_int(1).unwrap());
+//! }
+//! ```
+//!
+//! The branded API covers the most common operations. For anything else, use
+//! [`BrandedEvalState::unbranded`] and [`BrandedValue::as_value`] to get at the
+//! regular API, and [`BrandedEvalState::adopt`] to brand the results.
+
+use crate::eval_state::EvalState;
+use crate::value::{Int, Value, ValueType};
+use anyhow::Result;
+use std::marker::PhantomData;
+
+/// A unique, invariant lifetime that identifies one [`EvalState::with_brand`] call.
+///
+/// Invariance makes sure that the compiler can not unify two different brands.
+#[derive(Clone, Copy)]
+struct Brand<'id>(PhantomData<fn(&'id ()) -> &'id ()>);
+
+/// An [`EvalState`] whose values are tagged with the brand `'id`.
+///
+/// Created by [`EvalState::with_brand`].
+pub struct BrandedEvalState<'a, 'id> {
+    eval_state: &'a mut EvalState,
+    brand: Brand<'id>,
+}
+
+/// A [`Value`] that can only be used with the [`BrandedEvalState`] that has the same brand `'id`.
+#[derive(Clone)]
+pub struct BrandedValue<'id> {
+    value: Value,
+    _brand: Brand<'id>,
+}
+
+impl<'id> BrandedValue<'id> {
+    /// Returns the underlying unbranded value.
+    pub fn as_value(&self) -> &Value {
+        &self.value
+    }
+
+    /// Removes the brand.
+    ///
+    /// The caller is responsible for only using the returned value with the [`EvalState`] that created it.
+    pub fn into_value(self) -> Value {
+        self.value
+    }
+}
+
+impl std::fmt::Debug for BrandedValue<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.value, f)
+    }
+}
+
+impl EvalState {
+    /// Runs `f` with a [`BrandedEvalState`], whose values can not be mixed up with those of other `EvalState`s.
+    ///
+    /// See the [`branded`][`crate::branded`] module for an example.
+    #[doc(alias = "brand")]
+    pub fn with_brand<R>(&mut self, f: impl for<'id> FnOnce(BrandedEvalState<'_, 'id>) -> R) -> R {
+        f(BrandedEvalState {
+            eval_state: self,
+            brand: Brand(PhantomData),
+        })
+    }
+}
+
+impl<'id> BrandedEvalState<'_, 'id> {
+    fn brand(&self, value: Value) -> BrandedValue<'id> {
+        BrandedValue {
+            value,
+            _brand: self.brand,
+        }
+    }
+
+    /// Returns the underlying [`EvalState`], for operations that are not available in the branded API.
+    pub fn unbranded(&mut self) -> &mut EvalState {
+        self.eval_state
+    }
+
+    /// Brands a [`Value`], such as one returned by the [unbranded][`Self::unbranded`] API.
+    ///
+    /// The caller is responsible for only adopting values that were created by this `EvalState`.
+    pub fn adopt(&self, value: Value) -> BrandedValue<'id> {
+        self.brand(value)
+    }
+
+    /// See [`EvalState::eval_from_string`].
+    pub fn eval_from_string(&mut self, expr: &str, path: &str) -> Result<BrandedValue<'id>> {
+        let v = self.eval_state.eval_from_string(expr, path)?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::force`].
+    pub fn force(&mut self, v: &BrandedValue<'id>) -> Result<()> {
+        self.eval_state.force(&v.value)
+    }
+
+    /// See [`EvalState::value_type`].
+    pub fn value_type(&mut self, v: &BrandedValue<'id>) -> Result<ValueType> {
+        self.eval_state.value_type(&v.value)
+    }
+
+    /// See [`EvalState::require_int`].
+    pub fn require_int(&mut self, v: &BrandedValue<'id>) -> Result<Int> {
+        self.eval_state.require_int(&v.value)
+    }
+
+    /// See [`EvalState::require_bool`].
+    pub fn require_bool(&mut self, v: &BrandedValue<'id>) -> Result<bool> {
+        self.eval_state.require_bool(&v.value)
+    }
+
+    /// See [`EvalState::require_string`].
+    pub fn require_string(&mut self, v: &BrandedValue<'id>) -> Result<String> {
+        self.eval_state.require_string(&v.value)
+    }
+
+    /// See [`EvalState::require_attrs_names`].
+    pub fn require_attrs_names(&mut self, v: &BrandedValue<'id>) -> Result<Vec<String>> {
+        self.eval_state.require_attrs_names(&v.value)
+    }
+
+    /// See [`EvalState::require_attrs_select`].
+    pub fn require_attrs_select(
+        &mut self,
+        v: &BrandedValue<'id>,
+        attr_name: &str,
+    ) -> Result<BrandedValue<'id>> {
+        let r = self.eval_state.require_attrs_select(&v.value, attr_name)?;
+        Ok(self.brand(r))
+    }
+
+    /// See [`EvalState::require_attrs_select_opt`].
+    pub fn require_attrs_select_opt(
+        &mut self,
+        v: &BrandedValue<'id>,
+        attr_name: &str,
+    ) -> Result<Option<BrandedValue<'id>>> {
+        let r = self
+            .eval_state
+            .require_attrs_select_opt(&v.value, attr_name)?;
+        Ok(r.map(|r| self.brand(r)))
+    }
+
+    /// See [`EvalState::require_list_size`].
+    pub fn require_list_size(&mut self, v: &BrandedValue<'id>) -> Result<u32> {
+        self.eval_state.require_list_size(&v.value)
+    }
+
+    /// See [`EvalState::require_list_select_idx_strict`].
+    pub fn require_list_select_idx_strict(
+        &mut self,
+        v: &BrandedValue<'id>,
+        idx: u32,
+    ) -> Result<Option<BrandedValue<'id>>> {
+        let r = self
+            .eval_state
+            .require_list_select_idx_strict(&v.value, idx)?;
+        Ok(r.map(|r| self.brand(r)))
+    }
+
+    /// See [`EvalState::new_value_int`].
+    pub fn new_value_int(&mut self, i: Int) -> Result<BrandedValue<'id>> {
+        let v = self.eval_state.new_value_int(i)?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::new_value_str`].
+    pub fn new_value_str(&mut self, s: &str) -> Result<BrandedValue<'id>> {
+        let v = self.eval_state.new_value_str(s)?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::new_value_attrs`].
+    pub fn new_value_attrs<I>(&mut self, attrs: I) -> Result<BrandedValue<'id>>
+    where
+        I: IntoIterator<Item = (String, BrandedValue<'id>)>,
+    {
+        let v = self
+            .eval_state
+            .new_value_attrs(attrs.into_iter().map(|(k, v)| (k, v.value)))?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::call`].
+    pub fn call(
+        &mut self,
+        f: &BrandedValue<'id>,
+        a: &BrandedValue<'id>,
+    ) -> Result<BrandedValue<'id>> {
+        let v = self.eval_state.call(f.value.clone(), a.value.clone())?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::call_multi`].
+    pub fn call_multi(
+        &mut self,
+        f: &BrandedValue<'id>,
+        args: &[BrandedValue<'id>],
+    ) -> Result<BrandedValue<'id>> {
+        let args: Vec<Value> = args.iter().map(|a| a.value.clone()).collect();
+        let v = self.eval_state.call_multi(&f.value, &args)?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::new_value_apply`].
+    pub fn new_value_apply(
+        &mut self,
+        f: &BrandedValue<'id>,
+        a: &BrandedValue<'id>,
+    ) -> Result<BrandedValue<'id>> {
+        let v = self.eval_state.new_value_apply(&f.value, &a.value)?;
+        Ok(self.brand(v))
+    }
+
+    /// See [`EvalState::builtin`].
+    pub fn builtin(&mut self, name: &str) -> Result<BrandedValue<'id>> {
+        let v = self.eval_state.builtin(name)?;
+        Ok(self.brand(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::gc_register_my_thread;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    #[test]
+    fn branded_eval_and_call() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let r = es
+            .with_brand(|mut es| -> Result<_> {
+                let f = es.eval_from_string("a: b: { sum = a + b; }", "<test>")?;
+                let a = es.new_value_int(1)?;
+                let b = es.new_value_int(2)?;
+                let attrs = es.call_multi(&f, &[a, b])?;
+                let sum = es.require_attrs_select(&attrs, "sum")?;
+                es.require_int(&sum)
+            })
+            .unwrap();
+        assert_eq!(r, 3);
+    }
+
+    #[test]
+    fn branded_unbranded_round_trip() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let s = es
+            .with_brand(|mut es| -> Result<_> {
+                let v = es.new_value_str("hello")?;
+                let raw = v.clone().into_value();
+                let upper = es.unbranded().eval_from_string("s: s + \"!\"", "<test>")?;
+                let upper = es.adopt(upper);
+                let r = es.call(&upper, &v)?;
+                assert_eq!(es.unbranded().require_string(&raw)?, "hello");
+                es.require_string(&r)
+            })
+            .unwrap();
+        assert_eq!(s, "hello!");
+    }
+
+    #[test]
+    fn branded_attrs_and_lists() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        es.with_brand(|mut es| {
+            let one = es.new_value_int(1).unwrap();
+            let attrs = es.new_value_attrs([("one".to_string(), one)]).unwrap();
+            assert_eq!(es.require_attrs_names(&attrs).unwrap(), vec!["one"]);
+            assert!(es
+                .require_attrs_select_opt(&attrs, "two")
+                .unwrap()
+                .is_none());
+            let list = es.eval_from_string("[ 10 20 ]", "<test>").unwrap();
+            assert_eq!(es.require_list_size(&list).unwrap(), 2);
+            let second = es
+                .require_list_select_idx_strict(&list, 1)
+                .unwrap()
+                .unwrap();
+            assert_eq!(es.require_int(&second).unwrap(), 20);
+            assert_eq!(es.value_type(&list).unwrap(), ValueType::List);
+        });
+    }
+}
@@ -0,0 +1,257 @@
+//! # Recording Instantiated Derivations
+//!
+//! A [`DerivationRecorder`] collects the store derivations (`.drv` files) that are
+//! instantiated while evaluating, including intermediate ones and those needed for
+//! import from derivation, for example to add GC roots for them or to show a build plan.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::derivations::DerivationRecorder;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+//! let recorder = DerivationRecorder::new(&mut es)?;
+//! let v = recorder.eval_from_string(
+//!     &mut es,
+//!     r#"derivation { name = "hello"; builder = "/bin/sh"; system = "x86_64-linux"; }"#,
+//!     "<example>",
+//! )?;
+//! let drv_path = es.require_attrs_select(&v, "drvPath")?;
+//! es.force(&drv_path)?;
+//! let recorded = recorder.take();
+//! assert_eq!(recorded.len(), 1);
+//! assert_eq!(recorded[0].name, "hello");
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## How it works
+//!
+//! The recorder evaluates code with [`ScopedOverrides`], overriding `derivation` and
+//! `derivationStrict`. A derivation is recorded when the `drvPath` or `outPath` of one of its
+//! outputs is evaluated, which is when Nix writes the `.drv` file to the store. See
+//! [`ScopedOverrides`] for the limitations; in particular, the attribute path that caused an
+//! instantiation is not recorded, only the derivation name.
+
+use crate::eval_state::EvalState;
+use crate::primop::{PrimOp, PrimOpMeta};
+use crate::scoped::ScopedOverrides;
+use anyhow::Result;
+use cstr::cstr;
+use nix_bindings_store::path::StorePath;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// A store derivation that was instantiated during evaluation.
+#[derive(Clone)]
+pub struct InstantiatedDerivation {
+    /// The store path of the `.drv` file.
+    pub drv_path: StorePath,
+    /// The `name` of the derivation.
+    pub name: String,
+}
+
+/// The derivations recorded so far, in order, without duplicates.
+#[derive(Default)]
+struct Records {
+    derivations: Vec<InstantiatedDerivation>,
+    seen: HashSet<String>,
+}
+
+/// The hooks for [`ScopedOverrides`], given the recording primop.
+///
+/// `derivation` is replaced by a function that returns the same outputs, with
+/// `drvPath` and `outPath` wrapped in calls to `record`. The outputs refer to each other
+/// like those returned by the original `derivation`. The result of `derivationStrict` is
+/// recorded when it is evaluated, as that instantiates the derivation.
+const HOOKS_EXPR: &str = r#"
+record:
+let
+  wrap = drv:
+    let
+      outputNames = drv.outputs or [ "out" ];
+      wrapOutput = output: output // outputs // {
+        drvPath = record drv.name output.drvPath output.drvPath;
+        outPath = record drv.name output.drvPath output.outPath;
+        all = map (name: outputs.${name}) outputNames;
+      };
+      outputs = builtins.listToAttrs (map (name: { inherit name; value = wrapOutput drv.${name}; }) outputNames);
+    in
+    wrapOutput drv;
+  derivations = {
+    derivation = attrs: wrap (derivation attrs);
+    derivationStrict = attrs:
+      let drv = builtins.derivationStrict attrs;
+      in record attrs.name drv.drvPath drv;
+  };
+in
+{
+  toplevel = derivations;
+  builtins = derivations;
+}
+"#;
+
+/// Records the [`InstantiatedDerivation`]s of the code that it evaluates.
+///
+/// See the [module documentation](self) for an example and limitations.
+pub struct DerivationRecorder {
+    records: Rc<RefCell<Records>>,
+    overrides: ScopedOverrides,
+}
+
+impl DerivationRecorder {
+    /// Creates a recorder for evaluating with `eval_state`.
+    pub fn new(eval_state: &mut EvalState) -> Result<Self> {
+        let records = Rc::new(RefCell::new(Records::default()));
+        let primop_records = records.clone();
+        let primop = PrimOp::new(
+            eval_state,
+            PrimOpMeta {
+                name: cstr!("recordDerivation"),
+                doc: cstr!(
+                    "Records that a derivation was instantiated, and returns the value unchanged."
+                ),
+                args: [cstr!("name"), cstr!("drvPath"), cstr!("value")],
+            },
+            Box::new(move |es, [name, drv_path, value]| {
+                // Forcing the value first makes sure that the derivation has been instantiated.
+                es.force(value)?;
+                let drv_path = es.require_string(drv_path)?;
+                if !primop_records.borrow().seen.contains(&drv_path) {
+                    let derivation = InstantiatedDerivation {
+                        drv_path: es.store().clone().parse_store_path(&drv_path)?,
+                        name: es.require_string(name)?,
+                    };
+                    let mut records = primop_records.borrow_mut();
+                    records.seen.insert(drv_path);
+                    records.derivations.push(derivation);
+                }
+                Ok(value.clone())
+            }),
+        )?;
+        let overrides = ScopedOverrides::new(eval_state, HOOKS_EXPR, primop)?;
+        Ok(DerivationRecorder { records, overrides })
+    }
+
+    /// Returns the derivations that were instantiated since the recorder was created or since the last call to `take`,
+    /// in the order in which they were instantiated.
+    #[doc(alias = "finish")]
+    #[doc(alias = "drain")]
+    pub fn take(&self) -> Vec<InstantiatedDerivation> {
+        let mut records = self.records.borrow_mut();
+        records.seen.clear();
+        std::mem::take(&mut records.derivations)
+    }
+}
+
+/// Provides [`ScopedOverrides::import`] and [`ScopedOverrides::eval_from_string`] for evaluating with recording.
+impl Deref for DerivationRecorder {
+    type Target = ScopedOverrides;
+
+    fn deref(&self) -> &ScopedOverrides {
+        &self.overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    const DEPENDENCY: &str = r#"
+      let
+        dep = derivation {
+          name = "dep";
+          builder = "/bin/sh";
+          system = "x86_64-linux";
+          outputs = [ "out" "dev" ];
+        };
+      in
+      { inherit dep;
+        top = derivation {
+          name = "top";
+          builder = "/bin/sh";
+          system = "x86_64-linux";
+          inherit (dep) dev;
+        };
+        unused = derivation { name = "unused"; builder = "/bin/sh"; system = "x86_64-linux"; };
+      }
+    "#;
+
+    #[test]
+    fn derivations_recorded_with_dependencies() {
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let recorder = DerivationRecorder::new(&mut es).unwrap();
+            let v = recorder
+                .eval_from_string(&mut es, DEPENDENCY, "<test>")
+                .unwrap();
+            assert!(recorder.take().is_empty());
+
+            let top = es.require_attrs_select(&v, "top").unwrap();
+            let drv_path = es.require_attrs_select(&top, "drvPath").unwrap();
+            let drv_path = es.require_string(&drv_path).unwrap();
+
+            let recorded = recorder.take();
+            let names: Vec<&str> = recorded.iter().map(|d| d.name.as_str()).collect();
+            assert_eq!(names, vec!["dep", "top"]);
+            assert!(drv_path.ends_with("-top.drv"));
+            assert_eq!(recorded[1].drv_path.name().unwrap(), "top.drv");
+            assert!(recorder.take().is_empty());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn derivations_strict_recorded() {
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let recorder = DerivationRecorder::new(&mut es).unwrap();
+            let v = recorder
+                .eval_from_string(
+                    &mut es,
+                    r#"(builtins.derivationStrict { name = "strict"; builder = "/bin/sh"; system = "x86_64-linux"; }).out"#,
+                    "<test>",
+                )
+                .unwrap();
+            assert!(es.require_string(&v).unwrap().ends_with("-strict"));
+            let recorded = recorder.take();
+            assert_eq!(recorded.len(), 1);
+            assert_eq!(recorded[0].name, "strict");
+            assert_eq!(recorded[0].drv_path.name().unwrap(), "strict.drv");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn derivations_outputs_are_preserved() {
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let recorder = DerivationRecorder::new(&mut es).unwrap();
+            let v = recorder
+                .eval_from_string(
+                    &mut es,
+                    &format!(
+                        "let d = ({DEPENDENCY}).dep; in [ d.type d.outputName d.dev.outputName (builtins.length d.all) (d.dev.out.outPath == d.outPath) ]"
+                    ),
+                    "<test>",
+                )
+                .unwrap();
+            let printed = es
+                .print_value(&v, &crate::print::PrintOptions::default())
+                .unwrap();
+            assert_eq!(printed, "[ \"derivation\" \"out\" \"dev\" 2 true ]");
+            let names: Vec<String> = recorder.take().into_iter().map(|d| d.name).collect();
+            assert_eq!(names, vec!["dep"]);
+        })
+        .unwrap();
+    }
+}
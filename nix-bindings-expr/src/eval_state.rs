@@ -128,6 +128,7 @@
 //! # }
 //! ```
 
+use crate::function::{Formal, FunctionInfo};
 use crate::primop;
 use crate::value::{Int, Value, ValueType};
 use anyhow::Context as _;
@@ -143,6 +144,8 @@ use nix_bindings_util::string_return::{
     callback_get_result_string, callback_get_result_string_data,
 };
 use nix_bindings_util::{check_call, check_call_opt_key, result_string_init};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{c_char, CString};
 use std::iter::FromIterator;
 use std::os::raw::c_uint;
@@ -152,6 +155,7 @@ use std::sync::{Arc, LazyLock, Weak};
 static INIT: LazyLock<Result<()>> = LazyLock::new(|| unsafe {
     gc::GC_allow_register_threads();
     check_call!(raw::libexpr_init(&mut Context::new()))?;
+    crate::gc::install_collection_event_handler();
     Ok(())
 });
 
@@ -166,6 +170,26 @@ pub fn init() -> Result<()> {
     }
 }
 
+/// Whether `s` can be used as a variable name in the Nix language without quoting.
+pub(crate) fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    let valid_start = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_');
+    valid_start
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-')
+        && !matches!(
+            s,
+            "if" | "then" | "else" | "assert" | "with" | "let" | "in" | "rec" | "inherit" | "or"
+        )
+}
+
+/// Checks that `name` can be bound in a scope, as in [`EvalState::eval_from_string_with_scope`].
+pub(crate) fn check_scope_name(name: &str) -> Result<()> {
+    if !is_identifier(name) {
+        bail!("cannot bind `{}`: not a valid Nix identifier", name);
+    }
+    Ok(())
+}
+
 /// A string value with its associated [store paths](https://nix.dev/manual/nix/stable/store/store-path.html).
 ///
 /// Represents a Nix string with references to store paths.
@@ -176,6 +200,72 @@ pub struct RealisedString {
     pub paths: Vec<StorePath>,
 }
 
+/// A location in a Nix source file.
+///
+/// Returned by [`EvalState::attr_position`] and [`EvalState::function_position`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourcePosition {
+    /// The absolute path of the file.
+    pub file: String,
+    /// The line number, starting at 1.
+    pub line: u32,
+    /// The column number, starting at 1.
+    pub column: u32,
+}
+
+/// Returns the start tag of the first `element` in a `builtins.toXML` document, without the
+/// element name and the closing `>`.
+fn xml_start_tag<'a>(xml: &'a str, element: &str) -> Result<Option<&'a str>> {
+    let open = format!("<{}", element);
+    let mut from = 0;
+    while let Some(i) = xml[from..].find(&open) {
+        let rest = &xml[from + i + open.len()..];
+        if rest.starts_with([' ', '/', '>']) {
+            let end = rest.find('>').ok_or_else(|| {
+                anyhow::anyhow!("unterminated <{}> element in toXML output", element)
+            })?;
+            return Ok(Some(&rest[..end]));
+        }
+        from += i + open.len();
+    }
+    Ok(None)
+}
+
+/// Returns the unescaped value of the attribute `name` in a start tag from [`xml_start_tag`].
+fn xml_attr(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(
+        tag[start..start + len]
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// Reads the position of the root `<function>` element of a `builtins.toXML` document.
+///
+/// Primops are written as `<unevaluated />`, and lambdas that were not defined in a file have no `path`.
+fn parse_xml_function_position(xml: &str) -> Result<Option<SourcePosition>> {
+    let Some(tag) = xml_start_tag(xml, "function")? else {
+        return Ok(None);
+    };
+    let (Some(file), Some(line), Some(column)) = (
+        xml_attr(tag, "path"),
+        xml_attr(tag, "line"),
+        xml_attr(tag, "column"),
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some(SourcePosition {
+        file,
+        line: line.parse()?,
+        column: column.parse()?,
+    }))
+}
+
 /// A [Weak] reference to an [EvalState].
 pub struct EvalStateWeak {
     inner: Weak<EvalStateRef>,
@@ -198,6 +288,11 @@ impl EvalStateWeak {
 
 struct EvalStateRef {
     eval_state: NonNull<raw::EvalState>,
+    /// Values that are computed at most once per state, such as `builtins`. See [`EvalState::cached`].
+    cached_values: RefCell<HashMap<String, Value>>,
+    /// The configuration that this state was built with, for [`EvalState::reload`].
+    #[cfg(nix_at_least = "2.26")]
+    settings: BuilderSettings,
 }
 impl EvalStateRef {
     /// Returns a raw pointer to the underlying EvalState.
@@ -211,6 +306,8 @@ impl EvalStateRef {
 }
 impl Drop for EvalStateRef {
     fn drop(&mut self) {
+        // Release the cached values while the state is still around.
+        self.cached_values.get_mut().clear();
         unsafe {
             raw::state_free(self.eval_state.as_ptr());
         }
@@ -223,6 +320,11 @@ impl Drop for EvalStateRef {
 ///
 /// Requires Nix 2.26.0 or later.
 ///
+/// Evaluation settings such as `pure-eval`, `restrict-eval`, `allowed-uris`, `eval-system`,
+/// `nix-path` and `eval-profiler` can not be set for a single state, because the Nix C API
+/// only reads them from the Nix configuration files and the `NIX_CONFIG` environment variable,
+/// in [`load_ambient_settings`][`EvalStateBuilder::load_ambient_settings`].
+///
 /// # Examples
 ///
 /// ```rust
@@ -246,9 +348,17 @@ impl Drop for EvalStateRef {
 #[cfg(nix_at_least = "2.26")]
 pub struct EvalStateBuilder {
     eval_state_builder: *mut raw::eval_state_builder,
+    settings: BuilderSettings,
+    store: Store,
+}
+/// The configuration of an [`EvalStateBuilder`], which the built [`EvalState`] keeps for [`EvalState::reload`].
+#[cfg(nix_at_least = "2.26")]
+#[derive(Clone)]
+struct BuilderSettings {
     lookup_path: Vec<CString>,
+    /// Lookup path entries added by [`EvalStateBuilder::lookup_path_temp_dir`], with the directories they refer to.
+    temp_dirs: Vec<(CString, Arc<tempfile::TempDir>)>,
     load_ambient_settings: bool,
-    store: Store,
 }
 #[cfg(nix_at_least = "2.26")]
 impl Drop for EvalStateBuilder {
@@ -262,14 +372,23 @@ impl Drop for EvalStateBuilder {
 impl EvalStateBuilder {
     /// Creates a new [`EvalStateBuilder`].
     pub fn new(store: Store) -> Result<EvalStateBuilder> {
+        Self::with_settings(
+            store,
+            BuilderSettings {
+                lookup_path: Vec::new(),
+                temp_dirs: Vec::new(),
+                load_ambient_settings: true,
+            },
+        )
+    }
+    fn with_settings(store: Store, settings: BuilderSettings) -> Result<EvalStateBuilder> {
         let mut context = Context::new();
         let eval_state_builder =
             unsafe { check_call!(raw::eval_state_builder_new(&mut context, store.raw_ptr())) }?;
         Ok(EvalStateBuilder {
             store,
             eval_state_builder,
-            lookup_path: Vec::new(),
-            load_ambient_settings: true,
+            settings,
         })
     }
     /// Sets the [lookup path](https://nix.dev/manual/nix/latest/language/constructs/lookup-path.html) for Nix expression evaluation.
@@ -282,7 +401,78 @@ impl EvalStateBuilder {
                 })
             })
             .collect::<Result<_>>()?;
-        self.lookup_path = lookup_path;
+        self.settings.lookup_path = lookup_path;
+        Ok(self)
+    }
+    /// Writes `files` to a new temporary directory, and adds it to the lookup path as `<name>`,
+    /// for example for `import <name/default.nix>`.
+    ///
+    /// This is a convenience for evaluating generated or in-memory files, not a virtual filesystem:
+    /// the Nix C API does not support custom source accessors, so evaluation reads the files from disk.
+    ///
+    /// `files` maps paths relative to the directory to their contents, such as a
+    /// `HashMap<PathBuf, Vec<u8>>`. Subdirectories are created as needed. The directory is removed
+    /// when the built [`EvalState`]s and this builder have been dropped. Error messages and
+    /// `toString ./.` show its path, and changes to `files` after this call are not seen by evaluation.
+    ///
+    /// As the directory is not in the store, the files can not be read in pure evaluation mode.
+    /// In restricted evaluation mode they can, because the directory is on the lookup path.
+    #[doc(alias = "mount_files")]
+    #[doc(alias = "vfs")]
+    #[doc(alias = "in_memory")]
+    pub fn lookup_path_temp_dir<P, C>(
+        mut self,
+        name: &str,
+        files: impl IntoIterator<Item = (P, C)>,
+    ) -> Result<Self>
+    where
+        P: AsRef<std::path::Path>,
+        C: AsRef<[u8]>,
+    {
+        if !is_identifier(name) {
+            bail!(
+                "EvalStateBuilder::lookup_path_temp_dir: `{}` is not a valid lookup path name",
+                name
+            );
+        }
+        let dir = tempfile::Builder::new()
+            .prefix("nix-bindings-lookup-path-")
+            .tempdir()
+            .context(
+                "EvalStateBuilder::lookup_path_temp_dir: could not create temporary directory",
+            )?;
+        for (path, contents) in files {
+            let path = path.as_ref();
+            if path.as_os_str().is_empty()
+                || !path
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                bail!(
+                    "EvalStateBuilder::lookup_path_temp_dir: path `{}` must be relative, without `.` or `..`",
+                    path.display()
+                );
+            }
+            let target = dir.path().join(path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).with_context(|| {
+                    format!(
+                        "EvalStateBuilder::lookup_path_temp_dir: could not create directory for `{}`",
+                        path.display()
+                    )
+                })?;
+            }
+            std::fs::write(&target, contents).with_context(|| {
+                format!(
+                    "EvalStateBuilder::lookup_path_temp_dir: could not write `{}`",
+                    path.display()
+                )
+            })?;
+        }
+        let entry = CString::new(format!("{}={}", name, dir.path().display())).with_context(|| {
+            format!("EvalStateBuilder::lookup_path_temp_dir: temporary directory for `{name}` contains null byte")
+        })?;
+        self.settings.temp_dirs.push((entry, Arc::new(dir)));
         Ok(self)
     }
     /// Sets whether to load settings from the ambient environment.
@@ -291,7 +481,7 @@ impl EvalStateBuilder {
     /// from NIX_CONFIG and other environment variables. When disabled, only the
     /// explicitly configured settings are used.
     pub fn load_ambient_settings(mut self, load: bool) -> Self {
-        self.load_ambient_settings = load;
+        self.settings.load_ambient_settings = load;
         self
     }
     /// Builds the configured [`EvalState`].
@@ -303,7 +493,7 @@ impl EvalStateBuilder {
 
         // Load settings from global configuration (including readOnlyMode = false).
         // This is necessary for path coercion to work (adding files to the store).
-        if self.load_ambient_settings {
+        if self.settings.load_ambient_settings {
             unsafe {
                 check_call!(raw::eval_state_builder_load(
                     &mut context,
@@ -312,10 +502,12 @@ impl EvalStateBuilder {
             }
         }
 
-        // Note: these raw C string pointers borrow from self.lookup_path
+        // Note: these raw C string pointers borrow from self.settings.lookup_path and temp_dirs
         let mut lookup_path: Vec<*const c_char> = self
+            .settings
             .lookup_path
             .iter()
+            .chain(self.settings.temp_dirs.iter().map(|(entry, _)| entry))
             .map(|s| s.as_ptr())
             .chain(std::iter::once(null())) // signal the end of the array
             .collect();
@@ -335,6 +527,8 @@ impl EvalStateBuilder {
                 eval_state: NonNull::new(eval_state).unwrap_or_else(|| {
                     panic!("nix_state_create returned a null pointer without an error")
                 }),
+                cached_values: RefCell::new(HashMap::new()),
+                settings: self.settings.clone(),
             }),
             store: self.store.clone(),
             context,
@@ -375,6 +569,35 @@ impl EvalState {
         self.eval_state.as_ptr()
     }
 
+    /// Creates a new `EvalState` with the same store and configuration as this one, but with empty caches.
+    ///
+    /// An `EvalState` caches the files it has parsed and evaluated, and the source paths it has
+    /// copied to the store, for its whole lifetime. After files have been changed on disk, `import`
+    /// would keep returning their old contents, so long-running processes should reload instead.
+    ///
+    /// The Nix C API does not offer a way to clear the caches of an existing `EvalState`, so this
+    /// builds a new state. This is much cheaper than the evaluation it replaces, because the
+    /// evaluator is initialized lazily. Invalidating individual paths is not supported: all
+    /// cached files are read again.
+    ///
+    /// The new state is built from the same [`EvalStateBuilder`] configuration. With
+    /// [`load_ambient_settings`][`EvalStateBuilder::load_ambient_settings`], which is the default, the
+    /// configuration files and `NIX_CONFIG` are read again, so changes to them since this state was
+    /// built take effect. The Nix C API does not provide the resolved settings, so they can not be
+    /// carried over instead; disable `load_ambient_settings` to rule out such changes.
+    ///
+    /// [`Value`]s of this state must not be used with the new state. This state remains valid,
+    /// and keeps serving its cached contents.
+    #[cfg(nix_at_least = "2.26")]
+    #[doc(alias = "clear_cache")]
+    #[doc(alias = "invalidate")]
+    #[doc(alias = "reset")]
+    #[doc(alias = "refresh")]
+    pub fn reload(&self) -> Result<EvalState> {
+        EvalStateBuilder::with_settings(self.store.clone(), self.eval_state.settings.clone())?
+            .build()
+    }
+
     /// Returns a reference to the Store that's used for instantiation, import from derivation, etc.
     pub fn store(&self) -> &Store {
         &self.store
@@ -432,6 +655,102 @@ impl EvalState {
         }
     }
 
+    /// Parses and evaluates a Nix expression `expr` in which the names of `scope` are bound to their [`Value`]s.
+    ///
+    /// The names behave as if they were defined by a `let` around the expression, so they can be
+    /// referred to as free variables, and they shadow builtins such as `map` or `toString`.
+    /// Each name must be a valid Nix identifier.
+    ///
+    /// Like in [`eval_from_string`][`Self::eval_from_string`], relative path literals are resolved
+    /// against `path`. The names are bound by evaluating `expr` as the body of a function with the
+    /// names as parameters, which is written on the same line before `expr`, so line numbers in
+    /// error messages are those of `expr`, but columns on its first line are shifted by the
+    /// length of the parameter list. When `scope` is empty, `expr` is evaluated unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+    /// use nix_bindings_store::store::Store;
+    /// use std::collections::HashMap;
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// # test_init();
+    /// # let guard = gc_register_my_thread()?;
+    /// # let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+    /// let a = es.new_value_int(1)?;
+    /// let b = es.new_value_int(2)?;
+    /// let v = es.eval_from_string_with_scope(
+    ///     "a + b",
+    ///     ".",
+    ///     [("a".to_string(), a), ("b".to_string(), b)],
+    /// )?;
+    /// assert_eq!(es.require_int(&v)?, 3);
+    /// # drop(guard);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[doc(alias = "eval_with_scope")]
+    #[doc(alias = "eval_with_env")]
+    #[doc(alias = "variables")]
+    pub fn eval_from_string_with_scope<I>(
+        &mut self,
+        expr: &str,
+        path: &str,
+        scope: I,
+    ) -> Result<Value>
+    where
+        I: IntoIterator<Item = (String, Value)>,
+    {
+        let mut bindings = BTreeMap::new();
+        for (name, value) in scope {
+            check_scope_name(&name)?;
+            if bindings.contains_key(&name) {
+                bail!(
+                    "eval_from_string_with_scope: name `{}` is bound more than once",
+                    name
+                );
+            }
+            bindings.insert(name, value);
+        }
+        let names: Vec<String> = bindings.keys().cloned().collect();
+        let attrs = self.new_value_attrs(bindings)?;
+        self.eval_from_string_with_inherits(
+            expr,
+            path,
+            &[(attrs, names.iter().map(|n| n.as_str()).collect())],
+        )
+    }
+
+    /// Evaluates `expr` with the given attribute names inherited from their attribute sets, as in `let inherit (attrs) name1 name2; in expr`.
+    ///
+    /// The names must have been checked with [`check_scope_name`], and must not occur in more than one scope.
+    /// The attributes are not forced.
+    pub(crate) fn eval_from_string_with_inherits(
+        &mut self,
+        expr: &str,
+        path: &str,
+        scopes: &[(Value, Vec<&str>)],
+    ) -> Result<Value> {
+        let scopes: Vec<_> = scopes
+            .iter()
+            .filter(|(_, names)| !names.is_empty())
+            .collect();
+        if scopes.is_empty() {
+            return self.eval_from_string(expr, path);
+        }
+
+        // `{ a, b, ... }: { c, ... }: expr`, on the first line of `expr` to keep its line numbers
+        let mut source = String::new();
+        for (_, names) in &scopes {
+            source.push_str(&format!("{{ {}, ... }}: ", names.join(", ")));
+        }
+        source.push_str(expr);
+        let f = self.eval_from_string(&source, path)?;
+        let attrsets: Vec<Value> = scopes.iter().map(|(attrs, _)| attrs.clone()).collect();
+        self.call_multi(&f, &attrsets)
+    }
+
     /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) of a value to [weak head normal form](https://nix.dev/manual/nix/latest/language/evaluation.html?highlight=WHNF#values).
     ///
     /// Converts [thunks](https://nix.dev/manual/nix/latest/language/evaluation.html#laziness) to their evaluated form. Does not modify already-evaluated values.
@@ -452,6 +771,33 @@ impl EvalState {
         Ok(())
     }
 
+    /// Forces a value like [`force`][`Self::force`], but tells catchable errors apart from fatal ones,
+    /// like [`builtins.tryEval`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-tryEval).
+    ///
+    /// Returns:
+    /// - `Ok(Ok(()))` if the value evaluated successfully,
+    /// - `Ok(Err(e))` if it failed with an error that `tryEval` catches, i.e. `throw` or a failed `assert`,
+    /// - `Err(e)` for all other errors, such as `abort`, infinite recursion, exceeding `max-call-depth`,
+    ///   interrupts, and errors returned by primops.
+    ///
+    /// The value is forced once, and the error is classified by the type of the Nix exception,
+    /// which `tryEval` catches if it is an `AssertionError`, or its subclass `ThrowError`.
+    #[doc(alias = "tryEval")]
+    #[doc(alias = "catch")]
+    pub fn try_force(&mut self, v: &Value) -> Result<Result<()>> {
+        unsafe {
+            raw::value_force(self.context.ptr(), self.eval_state.as_ptr(), v.raw_ptr());
+        }
+        let name = self.context.err_name();
+        match self.context.check_err_and_clear() {
+            Ok(()) => Ok(Ok(())),
+            Err(e) => match name.as_deref() {
+                Some("nix::AssertionError" | "nix::ThrowError") => Ok(Err(e)),
+                _ => Err(e),
+            },
+        }
+    }
+
     /// Returns the type of a value without forcing [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html).
     ///
     /// Returns [`None`] if the value is an unevaluated [thunk](https://nix.dev/manual/nix/latest/language/evaluation.html#laziness).
@@ -1035,6 +1381,277 @@ impl EvalState {
         Ok(value)
     }
 
+    /// Returns the [`builtins`](https://nix.dev/manual/nix/latest/language/builtins.html) attribute set.
+    ///
+    /// The value is cached, so that this is cheap to call repeatedly.
+    #[doc(alias = "primops")]
+    #[doc(alias = "builtin_functions")]
+    pub fn builtins(&mut self) -> Result<Value> {
+        self.cached("builtins", |es| es.eval_from_string("builtins", "."))
+    }
+
+    /// Returns the attribute `name` of [`builtins`][`Self::builtins`], such as `"toJSON"` or `"currentSystem"`.
+    ///
+    /// The value is cached, so that this is cheap to call repeatedly.
+    #[doc(alias = "get_builtin")]
+    pub fn builtin(&mut self, name: &str) -> Result<Value> {
+        self.cached(&format!("builtins.{name}"), |es| {
+            let builtins = es.builtins()?;
+            es.require_attrs_select(&builtins, name)
+        })
+    }
+
+    /// Calls the builtin function `name` with `args`, like the Nix expression `builtins.<name> arg1 arg2 ...`.
+    ///
+    /// Returns the builtin itself when `args` is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+    /// # use nix_bindings_store::store::Store;
+    /// # use std::collections::HashMap;
+    /// # fn example() -> anyhow::Result<()> {
+    /// # test_init();
+    /// # let guard = gc_register_my_thread()?;
+    /// let store = Store::open(None, HashMap::new())?;
+    /// let mut es = EvalState::new(store, [])?;
+    ///
+    /// let v = es.eval_from_string("{ b = 1; a = [ true ]; }", "<example>")?;
+    /// let json = es.call_builtin("toJSON", &[v])?;
+    /// assert_eq!(es.require_string(&json)?, r#"{"a":[true],"b":1}"#);
+    /// # drop(guard);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[doc(alias = "builtins")]
+    #[doc(alias = "primop_call")]
+    pub fn call_builtin(&mut self, name: &str, args: &[Value]) -> Result<Value> {
+        let f = self.builtin(name)?;
+        if args.is_empty() {
+            return Ok(f);
+        }
+        self.call_multi(&f, args)
+    }
+
+    /// Returns [`builtins.currentSystem`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-currentSystem), the system type that evaluation is configured for, such as `"x86_64-linux"`.
+    #[doc(alias = "currentSystem")]
+    #[doc(alias = "system")]
+    pub fn current_system(&mut self) -> Result<String> {
+        let v = self.builtin("currentSystem")?;
+        self.require_string(&v)
+    }
+
+    /// Returns [`builtins.nixVersion`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-nixVersion), the version of the Nix evaluator.
+    #[doc(alias = "nixVersion")]
+    pub fn nix_version(&mut self) -> Result<String> {
+        let v = self.builtin("nixVersion")?;
+        self.require_string(&v)
+    }
+
+    /// Returns [`builtins.storeDir`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-storeDir), the logical location of the store, such as `"/nix/store"`.
+    #[doc(alias = "storeDir")]
+    pub fn store_dir(&mut self) -> Result<String> {
+        let v = self.builtin("storeDir")?;
+        self.require_string(&v)
+    }
+
+    /// Compares two values with the Nix [`==` operator](https://nix.dev/manual/nix/latest/language/operators.html#equality).
+    ///
+    /// This follows the evaluator's own semantics, for example:
+    /// - integers and floats are compared numerically, so `1 == 1.0`
+    /// - attribute sets and lists are compared deeply, forcing their contents as needed
+    /// - [derivations](https://nix.dev/manual/nix/latest/language/derivations.html) are compared by their `outPath` only
+    /// - functions are not equal to anything
+    ///
+    /// Returns an [`Err`] if evaluation of either value fails.
+    #[doc(alias = "eq")]
+    #[doc(alias = "equals")]
+    #[doc(alias = "==")]
+    pub fn values_equal(&mut self, a: &Value, b: &Value) -> Result<bool> {
+        let eq = self.cached("<nix-bindings-expr equal>", |es| {
+            es.eval_from_string("a: b: a == b", ".")
+        })?;
+        let r = self.call_multi(&eq, &[a.clone(), b.clone()])?;
+        self.require_bool(&r)
+    }
+
+    /// Orders two values with the Nix [`<` operator](https://nix.dev/manual/nix/latest/language/operators.html#comparison), for example for sorting.
+    ///
+    /// Numbers, strings, paths and lists (lexicographically, by their elements) can be compared.
+    ///
+    /// Returns [`Ordering::Equal`][`std::cmp::Ordering::Equal`] if neither value is less than the other.
+    ///
+    /// Returns an [`Err`] if evaluation fails, or if the values can't be compared, such as attribute sets.
+    #[doc(alias = "cmp")]
+    #[doc(alias = "lessThan")]
+    #[doc(alias = "<")]
+    pub fn compare(&mut self, a: &Value, b: &Value) -> Result<std::cmp::Ordering> {
+        let less_than = self.builtin("lessThan")?;
+        let r = self.call_multi(&less_than, &[a.clone(), b.clone()])?;
+        if self.require_bool(&r)? {
+            return Ok(std::cmp::Ordering::Less);
+        }
+        let r = self.call_multi(&less_than, &[b.clone(), a.clone()])?;
+        if self.require_bool(&r)? {
+            return Ok(std::cmp::Ordering::Greater);
+        }
+        Ok(std::cmp::Ordering::Equal)
+    }
+
+    /// Returns what kind of argument a [function][`ValueType::Function`] expects.
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) of `f` and verifies that it is a function.
+    /// Does not call the function.
+    ///
+    /// The Nix C API has no accessors for functions, so the kind of argument is read from the
+    /// output of [`builtins.toXML`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-toXML),
+    /// and the formals of a pattern from [`builtins.functionArgs`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-functionArgs).
+    ///
+    /// Note that attribute sets with a `__functor` attribute are not functions in this sense.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+    /// # use nix_bindings_expr::function::{Formal, FunctionInfo};
+    /// # use nix_bindings_store::store::Store;
+    /// # use std::collections::HashMap;
+    /// # fn example() -> anyhow::Result<()> {
+    /// # test_init();
+    /// # let guard = gc_register_my_thread()?;
+    /// let store = Store::open(None, HashMap::new())?;
+    /// let mut es = EvalState::new(store, [])?;
+    ///
+    /// let f = es.eval_from_string("{ pkgs, lib ? null, ... }: null", "<example>")?;
+    /// assert_eq!(
+    ///     es.function_info(&f)?,
+    ///     FunctionInfo::Pattern {
+    ///         formals: vec![
+    ///             Formal { name: "lib".to_string(), has_default: true },
+    ///             Formal { name: "pkgs".to_string(), has_default: false },
+    ///         ],
+    ///         ellipsis: true,
+    ///     }
+    /// );
+    ///
+    /// let f = es.eval_from_string("x: x", "<example>")?;
+    /// assert_eq!(es.function_info(&f)?, FunctionInfo::Lambda { arg: "x".to_string() });
+    /// # drop(guard);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[doc(alias = "functionArgs")]
+    #[doc(alias = "function_args")]
+    #[doc(alias = "formals")]
+    pub fn function_info(&mut self, f: &Value) -> Result<FunctionInfo> {
+        let xml = self.function_xml(f)?;
+        if xml_start_tag(&xml, "function")?.is_none() {
+            // Primops and their partial applications are written as `<unevaluated />`
+            return Ok(FunctionInfo::PrimOp);
+        }
+        if let Some(tag) = xml_start_tag(&xml, "varpat")? {
+            let arg = xml_attr(tag, "name")
+                .ok_or_else(|| anyhow::anyhow!("<varpat> element without name in toXML output"))?;
+            return Ok(FunctionInfo::Lambda { arg });
+        }
+        let tag = xml_start_tag(&xml, "attrspat")?
+            .ok_or_else(|| anyhow::anyhow!("<function> element without pattern in toXML output"))?;
+        let ellipsis = xml_attr(tag, "ellipsis").is_some();
+        let args = self.call_builtin("functionArgs", &[f.clone()])?;
+        let formals = self
+            .require_attrs_names(&args)?
+            .into_iter()
+            .map(|name| {
+                let has_default = self.require_attrs_select(&args, &name)?;
+                let has_default = self.require_bool(&has_default)?;
+                Ok(Formal { name, has_default })
+            })
+            .collect::<Result<_>>()?;
+        Ok(FunctionInfo::Pattern { formals, ellipsis })
+    }
+
+    /// Returns the position of the definition of attribute `name` in an [attribute set][`ValueType::AttrSet`], like [`builtins.unsafeGetAttrPos`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-unsafeGetAttrPos).
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) of `attrs`, but not of the attribute.
+    ///
+    /// Returns `Ok(None)` if the attribute does not exist, or if it was not defined in a file,
+    /// e.g. because it was defined in a string passed to [`Self::eval_from_string`], or created from Rust.
+    #[doc(alias = "unsafeGetAttrPos")]
+    #[doc(alias = "get_attr_pos")]
+    #[doc(alias = "location")]
+    pub fn attr_position(&mut self, attrs: &Value, name: &str) -> Result<Option<SourcePosition>> {
+        let t = self.value_type(attrs)?;
+        if t != ValueType::AttrSet {
+            bail!("expected an attrset, but got a {:?}", t);
+        }
+        let name = self.new_value_str(name)?;
+        let pos = self.call_builtin("unsafeGetAttrPos", &[name, attrs.clone()])?;
+        self.require_source_position(&pos)
+    }
+
+    /// Returns the position where a [function][`ValueType::Function`] is defined.
+    ///
+    /// Forces [evaluation](https://nix.dev/manual/nix/latest/language/evaluation.html) of `f` and verifies that it is a function.
+    ///
+    /// This is the position of the lambda itself, e.g. of `x` in `x: ...` and of `{` in `{ a, b }: ...`.
+    /// The Nix C API has no accessor for it, so it is read from the `path`, `line` and `column` that
+    /// [`builtins.toXML`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-toXML)
+    /// puts on the function element.
+    ///
+    /// Returns `Ok(None)` for primops, and for functions that were not defined in a file.
+    #[doc(alias = "location")]
+    pub fn function_position(&mut self, f: &Value) -> Result<Option<SourcePosition>> {
+        let xml = self.function_xml(f)?;
+        parse_xml_function_position(&xml)
+    }
+
+    /// Checks that `f` is a [function][`ValueType::Function`] and returns its `builtins.toXML` representation.
+    fn function_xml(&mut self, f: &Value) -> Result<String> {
+        let t = self.value_type(f)?;
+        if t != ValueType::Function {
+            bail!("expected a function, but got a {:?}", t);
+        }
+        let xml = self.call_builtin("toXML", &[f.clone()])?;
+        self.require_string(&xml)
+    }
+
+    /// Converts the result of `builtins.unsafeGetAttrPos` to a [`SourcePosition`].
+    fn require_source_position(&mut self, pos: &Value) -> Result<Option<SourcePosition>> {
+        if self.value_type(pos)? == ValueType::Null {
+            return Ok(None);
+        }
+        let file = self.require_attrs_select(pos, "file")?;
+        let line = self.require_attrs_select(pos, "line")?;
+        let column = self.require_attrs_select(pos, "column")?;
+        Ok(Some(SourcePosition {
+            file: self.require_string(&file)?,
+            line: self.require_int(&line)?.try_into()?,
+            column: self.require_int(&column)?.try_into()?,
+        }))
+    }
+
+    /// Returns the value for `key`, computing it with `f` only if it hasn't been computed before.
+    ///
+    /// The cache is shared between clones of this `EvalState` and lives as long as the underlying state.
+    /// Errors are not cached.
+    fn cached(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut EvalState) -> Result<Value>,
+    ) -> Result<Value> {
+        if let Some(v) = self.eval_state.cached_values.borrow().get(key) {
+            return Ok(v.clone());
+        }
+        // Not holding the borrow here, because `f` may use the cache too.
+        let v = f(self)?;
+        self.eval_state
+            .cached_values
+            .borrow_mut()
+            .insert(key.to_owned(), v.clone());
+        Ok(v)
+    }
+
     fn new_value_uninitialized(&mut self) -> Result<Value> {
         unsafe {
             let value = check_call!(raw::alloc_value(
@@ -1183,191 +1800,724 @@ impl Drop for ThreadRegistrationGuard {
             unsafe {
                 gc::GC_unregister_my_thread();
             }
-        }
+        }
+    }
+}
+
+fn gc_register_my_thread_do_it() -> Result<()> {
+    unsafe {
+        let mut sb: gc::GC_stack_base = gc::GC_stack_base {
+            mem_base: null_mut(),
+        };
+        let r = gc::GC_get_stack_base(&mut sb);
+        if r as u32 != gc::GC_SUCCESS {
+            Err(anyhow::format_err!("GC_get_stack_base failed: {}", r))?;
+        }
+        gc::GC_register_my_thread(&sb);
+        Ok(())
+    }
+}
+
+#[doc(alias = "register_thread")]
+#[doc(alias = "thread_setup")]
+#[doc(alias = "gc_register")]
+pub fn gc_register_my_thread() -> Result<ThreadRegistrationGuard> {
+    init()?;
+    unsafe {
+        let already_done = gc::GC_thread_is_registered();
+        if already_done != 0 {
+            return Ok(ThreadRegistrationGuard {
+                must_unregister: false,
+            });
+        }
+        gc_register_my_thread_do_it()?;
+        Ok(ThreadRegistrationGuard {
+            must_unregister: true,
+        })
+    }
+}
+
+impl Clone for EvalState {
+    fn clone(&self) -> Self {
+        EvalState {
+            eval_state: self.eval_state.clone(),
+            store: self.store.clone(),
+            context: Context::new(),
+        }
+    }
+}
+
+/// Initialize the Nix library for testing. This includes some modifications to the Nix settings, that must not be used in production.
+/// Use at your own peril, in rust test suites.
+#[doc(alias = "test_initialize")]
+#[doc(alias = "test_setup")]
+pub fn test_init() {
+    init().unwrap();
+
+    // During development, we encountered a problem where the build hook
+    // would cause the test suite to reinvokes itself, causing an infinite loop.
+    // While _NIX_TEST_NO_SANDBOX=1 should prevent this, we may also set the
+    // build hook to "" to prevent this.
+    nix_bindings_util::settings::set("build-hook", "").unwrap();
+
+    // When testing in the sandbox, the default build dir would be a parent of the storeDir,
+    // which causes an error. So we set a custom build dir here.
+    // Only available on linux
+    if cfg!(target_os = "linux") {
+        nix_bindings_util::settings::set("sandbox-build-dir", "/custom-build-dir-for-test")
+            .unwrap();
+    }
+    std::env::set_var("_NIX_TEST_NO_SANDBOX", "1");
+
+    // The tests run offline
+    nix_bindings_util::settings::set("substituters", "").unwrap();
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use cstr::cstr;
+    use ctor::ctor;
+    use std::collections::HashMap;
+    use std::fs::read_dir;
+    use std::io::Write as _;
+    use std::sync::{Arc, Mutex};
+
+    #[ctor]
+    fn setup() {
+        test_init();
+
+        // Configure Nix settings for the test suite
+        // Set max-call-depth to 1000 (lower than default 10000) for the
+        // eval_state_builder_loads_max_call_depth test case, while
+        // giving other tests sufficient room for normal evaluation.
+        std::env::set_var("NIX_CONFIG", "max-call-depth = 1000");
+    }
+
+    /// Run a function while making sure that the current thread is registered with the GC.
+    pub fn gc_registering_current_thread<F, R>(f: F) -> Result<R>
+    where
+        F: FnOnce() -> R,
+    {
+        let guard = gc_register_my_thread()?;
+        let r = f();
+        drop(guard);
+        Ok(r)
+    }
+
+    #[test]
+    fn eval_state_new_and_drop() {
+        gc_registering_current_thread(|| {
+            // very basic test: make sure initialization doesn't crash
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let _e = EvalState::new(store, []).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn weak_ref() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let es = EvalState::new(store, []).unwrap();
+            let weak = es.weak_ref();
+            let _es = weak.upgrade().unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn weak_ref_gone() {
+        gc_registering_current_thread(|| {
+            let weak = {
+                // Use a slightly different URL which is unique in the test suite, to bypass the global store cache
+                let store = Store::open(Some("auto?foo=bar"), HashMap::new()).unwrap();
+                let es = EvalState::new(store, []).unwrap();
+                es.weak_ref()
+            };
+            assert!(weak.upgrade().is_none());
+            assert!(weak.store.upgrade().is_none());
+            assert!(weak.inner.upgrade().is_none());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_reload() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "1").unwrap();
+        let expr = format!("import {}", file.path().display());
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let v = es.eval_from_string(&expr, "<test>").unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 1);
+
+            let f = file.reopen().unwrap();
+            f.set_len(0).unwrap();
+            writeln!(&f, "2").unwrap();
+
+            // The old state keeps the parsed file
+            let v = es.eval_from_string(&expr, "<test>").unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 1);
+
+            let mut es2 = es.reload().unwrap();
+            let v = es2.eval_from_string(&expr, "<test>").unwrap();
+            assert_eq!(es2.require_int(&v).unwrap(), 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_reload_keeps_lookup_path() {
+        let mut test_file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(test_file, "42").unwrap();
+        gc_registering_current_thread(|| {
+            let es = EvalStateBuilder::new(Store::open(None, HashMap::new()).unwrap())
+                .unwrap()
+                .lookup_path([format!("answer={}", test_file.path().display()).as_str()])
+                .unwrap()
+                .build()
+                .unwrap();
+            let mut es2 = es.reload().unwrap();
+            let v = es2.eval_from_string("import <answer>", "<test>").unwrap();
+            assert_eq!(es2.require_int(&v).unwrap(), 42);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_lookup_path() {
+        let import_expression = "import <test_file0> + import <test_file1>";
+        let integer0 = 83;
+        let integer1 = 103;
+        let mut test_file0 = tempfile::NamedTempFile::new().unwrap();
+        let mut test_file1 = tempfile::NamedTempFile::new().unwrap();
+        writeln!(test_file0, "{integer0}").unwrap();
+        writeln!(test_file1, "{integer1}").unwrap();
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            assert!(es.eval_from_string(import_expression, "<test>").is_err());
+
+            let mut es = EvalState::new(
+                Store::open(None, HashMap::new()).unwrap(),
+                [
+                    format!("test_file0={}", test_file0.path().to_str().unwrap()).as_str(),
+                    format!("test_file1={}", test_file1.path().to_str().unwrap()).as_str(),
+                ],
+            )
+            .unwrap();
+            let ie = &es.eval_from_string(import_expression, "<test>").unwrap();
+            let v = es.require_int(ie).unwrap();
+            assert_eq!(v, integer0 + integer1);
+        })
+        .unwrap();
+        test_file0.close().unwrap();
+        test_file1.close().unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.eval_from_string("1", "<test>").unwrap();
+            let v2 = v.clone();
+            es.force(&v).unwrap();
+            let t = es.value_type_unforced(&v);
+            assert!(t == Some(ValueType::Int));
+            let t2 = es.value_type_unforced(&v2);
+            assert!(t2 == Some(ValueType::Int));
+            gc_now();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es.new_value_int(1).unwrap();
+            let b = es.new_value_str("two").unwrap();
+            let v = es
+                .eval_from_string_with_scope(
+                    "\"${toString a} ${b}\"",
+                    "<test>",
+                    [("a".to_string(), a), ("b".to_string(), b)],
+                )
+                .unwrap();
+            assert_eq!(es.require_string(&v).unwrap(), "1 two");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope_empty() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string_with_scope("1 + 1", "<test>", [])
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope_shadows_builtins() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let t = es.new_value_int(42).unwrap();
+            let v = es
+                .eval_from_string_with_scope("toString", "<test>", [("toString".to_string(), t)])
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 42);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope_bad_names() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es.new_value_int(1).unwrap();
+            for name in ["", "1a", "a b", "let"] {
+                let r =
+                    es.eval_from_string_with_scope("1", "<test>", [(name.to_string(), v.clone())]);
+                assert!(r.is_err(), "expected an error for name `{}`", name);
+            }
+            let r = es.eval_from_string_with_scope(
+                "a",
+                "<test>",
+                [("a".to_string(), v.clone()), ("a".to_string(), v)],
+            );
+            assert!(r.unwrap_err().to_string().contains("more than once"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope_error_line() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es.new_value_int(1).unwrap();
+            let r = es.eval_from_string_with_scope(
+                "a +\n  throw \"oops\"",
+                "<test>",
+                [("a".to_string(), a)],
+            );
+            let e = r.unwrap_err().to_string();
+            assert!(e.contains("oops"), "unexpected error: {}", e);
+            assert!(e.contains(":2:"), "expected an error on line 2: {}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope_error_column() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es.new_value_int(1).unwrap();
+            let r = es.eval_from_string_with_scope(
+                "a + undefinedName",
+                "<test>",
+                [("a".to_string(), a)],
+            );
+            let e = r.unwrap_err().to_string();
+            assert!(e.contains("undefinedName"), "unexpected error: {}", e);
+            // Shifted by the length of `{ a, ... }: `
+            assert!(
+                e.contains(":1:17"),
+                "expected an error at line 1, column 17: {}",
+                e
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_with_scope_relative_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es.new_value_str("file.nix").unwrap();
+            let v = es
+                .eval_from_string_with_scope(
+                    "toString ./. + \"/${a}\"",
+                    "/some/dir",
+                    [("a".to_string(), a)],
+                )
+                .unwrap();
+            assert_eq!(es.require_string(&v).unwrap(), "/some/dir/file.nix");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builtins() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let builtins = es.builtins().unwrap();
+            let names = es.require_attrs_names(&builtins).unwrap();
+            assert!(names.contains(&"toJSON".to_string()));
+            assert!(names.contains(&"mapAttrs".to_string()));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builtin_cached() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es.builtin("attrNames").unwrap();
+            let b = es.clone().builtin("attrNames").unwrap();
+            assert_eq!(unsafe { a.raw_ptr() }, unsafe { b.raw_ptr() });
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builtin_missing() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let r = es.call_builtin("doesNotExist", &[]);
+            assert!(r.unwrap_err().to_string().contains("doesNotExist"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_call_builtin() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let attrs = es.eval_from_string("{ b = 2; a = 1; }", "<test>").unwrap();
+
+            let names = es.call_builtin("attrNames", &[attrs.clone()]).unwrap();
+            let names: Vec<Value> = es.require_list_strict(&names).unwrap();
+            let names: Vec<String> = names
+                .iter()
+                .map(|v| es.require_string(v).unwrap())
+                .collect();
+            assert_eq!(names, vec!["a", "b"]);
+
+            let f = es
+                .eval_from_string("name: value: value * 10", "<test>")
+                .unwrap();
+            let mapped = es.call_builtin("mapAttrs", &[f, attrs]).unwrap();
+            let json = es.call_builtin("toJSON", &[mapped]).unwrap();
+            assert_eq!(es.require_string(&json).unwrap(), r#"{"a":10,"b":20}"#);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builtin_constants() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let system = es.current_system().unwrap();
+            assert!(system.contains('-'), "unexpected system: {}", system);
+            let version = es.nix_version().unwrap();
+            assert!(version.starts_with("2."), "unexpected version: {}", version);
+            let store_dir = es.store_dir().unwrap();
+            #[cfg(nix_at_least = "2.26")]
+            assert_eq!(store_dir, es.store().clone().get_storedir().unwrap());
+            assert!(
+                store_dir.starts_with('/'),
+                "unexpected store dir: {}",
+                store_dir
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_function_info_lambda() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let f = es.eval_from_string("x: x", "<test>").unwrap();
+            assert_eq!(
+                es.function_info(&f).unwrap(),
+                FunctionInfo::Lambda {
+                    arg: "x".to_string()
+                }
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_function_info_pattern() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let f = es
+                .eval_from_string("args@{ b ? 1, a }: a + b", "<test>")
+                .unwrap();
+            assert_eq!(
+                es.function_info(&f).unwrap(),
+                FunctionInfo::Pattern {
+                    formals: vec![
+                        Formal {
+                            name: "a".to_string(),
+                            has_default: false
+                        },
+                        Formal {
+                            name: "b".to_string(),
+                            has_default: true
+                        },
+                    ],
+                    ellipsis: false,
+                }
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_function_info_empty_pattern() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            for (expr, ellipsis) in [("{ }: null", false), ("{ ... }: null", true)] {
+                let f = es.eval_from_string(expr, "<test>").unwrap();
+                assert_eq!(
+                    es.function_info(&f).unwrap(),
+                    FunctionInfo::Pattern {
+                        formals: vec![],
+                        ellipsis
+                    },
+                    "{}",
+                    expr
+                );
+            }
+        })
+        .unwrap();
     }
-}
 
-fn gc_register_my_thread_do_it() -> Result<()> {
-    unsafe {
-        let mut sb: gc::GC_stack_base = gc::GC_stack_base {
-            mem_base: null_mut(),
-        };
-        let r = gc::GC_get_stack_base(&mut sb);
-        if r as u32 != gc::GC_SUCCESS {
-            Err(anyhow::format_err!("GC_get_stack_base failed: {}", r))?;
-        }
-        gc::GC_register_my_thread(&sb);
-        Ok(())
+    #[test]
+    fn eval_state_function_info_builtin() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            for expr in ["builtins.map", "builtins.map (x: x)"] {
+                let f = es.eval_from_string(expr, "<test>").unwrap();
+                assert_eq!(
+                    es.function_info(&f).unwrap(),
+                    FunctionInfo::PrimOp,
+                    "{}",
+                    expr
+                );
+            }
+        })
+        .unwrap();
     }
-}
 
-#[doc(alias = "register_thread")]
-#[doc(alias = "thread_setup")]
-#[doc(alias = "gc_register")]
-pub fn gc_register_my_thread() -> Result<ThreadRegistrationGuard> {
-    init()?;
-    unsafe {
-        let already_done = gc::GC_thread_is_registered();
-        if already_done != 0 {
-            return Ok(ThreadRegistrationGuard {
-                must_unregister: false,
-            });
-        }
-        gc_register_my_thread_do_it()?;
-        Ok(ThreadRegistrationGuard {
-            must_unregister: true,
+    #[test]
+    fn eval_state_function_info_primop() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let primop = primop::PrimOp::new(
+                &mut es,
+                primop::PrimOpMeta {
+                    name: cstr!("functionInfoTest"),
+                    args: [cstr!("a"), cstr!("b")],
+                    doc: cstr!("test function"),
+                },
+                Box::new(|es, [a, _b]| {
+                    let a = es.require_int(a)?;
+                    es.new_value_int(a)
+                }),
+            )
+            .unwrap();
+            let f = es.new_value_primop(primop).unwrap();
+            assert_eq!(es.function_info(&f).unwrap(), FunctionInfo::PrimOp);
         })
+        .unwrap();
     }
-}
 
-impl Clone for EvalState {
-    fn clone(&self) -> Self {
-        EvalState {
-            eval_state: self.eval_state.clone(),
-            store: self.store.clone(),
-            context: Context::new(),
-        }
+    #[test]
+    fn eval_state_function_info_not_a_function() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let v = es
+                .eval_from_string("{ __functor = self: x: x; }", "<test>")
+                .unwrap();
+            let r = es.function_info(&v);
+            assert!(r
+                .unwrap_err()
+                .to_string()
+                .contains("expected a function, but got a AttrSet"));
+        })
+        .unwrap();
     }
-}
-
-/// Initialize the Nix library for testing. This includes some modifications to the Nix settings, that must not be used in production.
-/// Use at your own peril, in rust test suites.
-#[doc(alias = "test_initialize")]
-#[doc(alias = "test_setup")]
-pub fn test_init() {
-    init().unwrap();
 
-    // During development, we encountered a problem where the build hook
-    // would cause the test suite to reinvokes itself, causing an infinite loop.
-    // While _NIX_TEST_NO_SANDBOX=1 should prevent this, we may also set the
-    // build hook to "" to prevent this.
-    nix_bindings_util::settings::set("build-hook", "").unwrap();
-
-    // When testing in the sandbox, the default build dir would be a parent of the storeDir,
-    // which causes an error. So we set a custom build dir here.
-    // Only available on linux
-    if cfg!(target_os = "linux") {
-        nix_bindings_util::settings::set("sandbox-build-dir", "/custom-build-dir-for-test")
-            .unwrap();
-    }
-    std::env::set_var("_NIX_TEST_NO_SANDBOX", "1");
+    #[test]
+    fn eval_state_source_positions() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "{{\n  a = 1;\n  f = {{ y ? 2, x }}: x;\n  g = x: x;\n}}\n"
+        )
+        .unwrap();
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let path = es.new_value_str(file.path().to_str().unwrap()).unwrap();
+            let attrs = es.call_builtin("import", &[path]).unwrap();
+            let file_name = file.path().to_str().unwrap().to_string();
 
-    // The tests run offline
-    nix_bindings_util::settings::set("substituters", "").unwrap();
-}
+            assert_eq!(
+                es.attr_position(&attrs, "a").unwrap(),
+                Some(SourcePosition {
+                    file: file_name.clone(),
+                    line: 2,
+                    column: 3
+                })
+            );
+            assert_eq!(es.attr_position(&attrs, "missing").unwrap(), None);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use cstr::cstr;
-    use ctor::ctor;
-    use std::collections::HashMap;
-    use std::fs::read_dir;
-    use std::io::Write as _;
-    use std::sync::{Arc, Mutex};
+            let f = es.require_attrs_select(&attrs, "f").unwrap();
+            assert_eq!(
+                es.function_position(&f).unwrap(),
+                Some(SourcePosition {
+                    file: file_name.clone(),
+                    line: 3,
+                    column: 7
+                })
+            );
 
-    #[ctor]
-    fn setup() {
-        test_init();
+            let g = es.require_attrs_select(&attrs, "g").unwrap();
+            assert_eq!(
+                es.function_position(&g).unwrap(),
+                Some(SourcePosition {
+                    file: file_name,
+                    line: 4,
+                    column: 7
+                })
+            );
 
-        // Configure Nix settings for the test suite
-        // Set max-call-depth to 1000 (lower than default 10000) for the
-        // eval_state_builder_loads_max_call_depth test case, while
-        // giving other tests sufficient room for normal evaluation.
-        std::env::set_var("NIX_CONFIG", "max-call-depth = 1000");
+            let map = es.builtin("map").unwrap();
+            assert_eq!(es.function_position(&map).unwrap(), None);
+        })
+        .unwrap();
+        file.close().unwrap();
     }
 
-    /// Run a function while making sure that the current thread is registered with the GC.
-    pub fn gc_registering_current_thread<F, R>(f: F) -> Result<R>
-    where
-        F: FnOnce() -> R,
-    {
-        let guard = gc_register_my_thread()?;
-        let r = f();
-        drop(guard);
-        Ok(r)
+    #[test]
+    fn parse_xml_function_position_escaped_path() {
+        let xml = "<?xml version='1.0' encoding='utf-8'?>\n<expr>\n  <function column=\"3\" line=\"12\" path=\"/a &amp; b/&quot;c&quot;.nix\">\n    <varpat name=\"x\" />\n  </function>\n</expr>\n";
+        assert_eq!(
+            parse_xml_function_position(xml).unwrap(),
+            Some(SourcePosition {
+                file: "/a & b/\"c\".nix".to_string(),
+                line: 12,
+                column: 3
+            })
+        );
+        let xml = "<?xml version='1.0' encoding='utf-8'?>\n<expr>\n  <unevaluated />\n</expr>\n";
+        assert_eq!(parse_xml_function_position(xml).unwrap(), None);
     }
 
     #[test]
-    fn eval_state_new_and_drop() {
-        gc_registering_current_thread(|| {
-            // very basic test: make sure initialization doesn't crash
-            let store = Store::open(None, HashMap::new()).unwrap();
-            let _e = EvalState::new(store, []).unwrap();
-        })
-        .unwrap();
+    fn xml_start_tag_patterns() {
+        let xml = "<expr>\n  <function>\n    <attrspat ellipsis=\"1\" name=\"args\">\n      <attr name=\"a\" />\n    </attrspat>\n  </function>\n</expr>\n";
+        assert_eq!(xml_start_tag(xml, "varpat").unwrap(), None);
+        let tag = xml_start_tag(xml, "attrspat").unwrap().unwrap();
+        assert_eq!(xml_attr(tag, "ellipsis").as_deref(), Some("1"));
+        assert_eq!(xml_attr(tag, "name").as_deref(), Some("args"));
+        let tag = xml_start_tag(xml, "attr").unwrap().unwrap();
+        assert_eq!(xml_attr(tag, "name").as_deref(), Some("a"));
+
+        let xml = "<expr>\n  <function>\n    <attrspat>\n    </attrspat>\n  </function>\n</expr>\n";
+        let tag = xml_start_tag(xml, "attrspat").unwrap().unwrap();
+        assert_eq!(xml_attr(tag, "ellipsis"), None);
     }
 
     #[test]
-    fn weak_ref() {
+    fn eval_state_source_positions_from_string() {
         gc_registering_current_thread(|| {
             let store = Store::open(None, HashMap::new()).unwrap();
-            let es = EvalState::new(store, []).unwrap();
-            let weak = es.weak_ref();
-            let _es = weak.upgrade().unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let attrs = es.eval_from_string("{ a = 1; }", "<test>").unwrap();
+            assert_eq!(es.attr_position(&attrs, "a").unwrap(), None);
+            let f = es.eval_from_string("x: x", "<test>").unwrap();
+            assert_eq!(es.function_position(&f).unwrap(), None);
+            let r = es.function_position(&attrs);
+            assert!(r.is_err());
         })
         .unwrap();
     }
 
     #[test]
-    fn weak_ref_gone() {
+    fn eval_state_values_equal() {
         gc_registering_current_thread(|| {
-            let weak = {
-                // Use a slightly different URL which is unique in the test suite, to bypass the global store cache
-                let store = Store::open(Some("auto?foo=bar"), HashMap::new()).unwrap();
-                let es = EvalState::new(store, []).unwrap();
-                es.weak_ref()
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let mut eq = |a: &str, b: &str| {
+                let a = es.eval_from_string(a, "<test>").unwrap();
+                let b = es.eval_from_string(b, "<test>").unwrap();
+                es.values_equal(&a, &b).unwrap()
             };
-            assert!(weak.upgrade().is_none());
-            assert!(weak.store.upgrade().is_none());
-            assert!(weak.inner.upgrade().is_none());
+            assert!(eq("1", "1"));
+            assert!(eq("1", "1.0"));
+            assert!(!eq("1", "2"));
+            assert!(!eq("1", "\"1\""));
+            assert!(eq("{ a = [ 1 { b = 2; } ]; }", "{ a = [ 1 { b = 2; } ]; }"));
+            assert!(!eq("{ a = 1; }", "{ a = 1; b = 2; }"));
+            assert!(eq(
+                "{ type = \"derivation\"; outPath = \"/foo\"; x = 1; }",
+                "{ type = \"derivation\"; outPath = \"/foo\"; x = 2; }"
+            ));
+            assert!(!eq("x: x", "x: x"));
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_lookup_path() {
-        let import_expression = "import <test_file0> + import <test_file1>";
-        let integer0 = 83;
-        let integer1 = 103;
-        let mut test_file0 = tempfile::NamedTempFile::new().unwrap();
-        let mut test_file1 = tempfile::NamedTempFile::new().unwrap();
-        writeln!(test_file0, "{integer0}").unwrap();
-        writeln!(test_file1, "{integer1}").unwrap();
+    fn eval_state_values_equal_error() {
         gc_registering_current_thread(|| {
-            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
-            assert!(es.eval_from_string(import_expression, "<test>").is_err());
-
-            let mut es = EvalState::new(
-                Store::open(None, HashMap::new()).unwrap(),
-                [
-                    format!("test_file0={}", test_file0.path().to_str().unwrap()).as_str(),
-                    format!("test_file1={}", test_file1.path().to_str().unwrap()).as_str(),
-                ],
-            )
-            .unwrap();
-            let ie = &es.eval_from_string(import_expression, "<test>").unwrap();
-            let v = es.require_int(ie).unwrap();
-            assert_eq!(v, integer0 + integer1);
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let a = es
+                .eval_from_string("[ (throw \"oops\") ]", "<test>")
+                .unwrap();
+            let b = es.eval_from_string("[ 1 ]", "<test>").unwrap();
+            let r = es.values_equal(&a, &b);
+            assert!(r.unwrap_err().to_string().contains("oops"));
         })
         .unwrap();
-        test_file0.close().unwrap();
-        test_file1.close().unwrap();
     }
 
     #[test]
-    fn eval_state_eval_from_string() {
+    fn eval_state_compare() {
         gc_registering_current_thread(|| {
+            use std::cmp::Ordering;
             let store = Store::open(None, HashMap::new()).unwrap();
             let mut es = EvalState::new(store, []).unwrap();
-            let v = es.eval_from_string("1", "<test>").unwrap();
-            let v2 = v.clone();
-            es.force(&v).unwrap();
-            let t = es.value_type_unforced(&v);
-            assert!(t == Some(ValueType::Int));
-            let t2 = es.value_type_unforced(&v2);
-            assert!(t2 == Some(ValueType::Int));
-            gc_now();
+            let mut cmp = |a: &str, b: &str| {
+                let a = es.eval_from_string(a, "<test>").unwrap();
+                let b = es.eval_from_string(b, "<test>").unwrap();
+                es.compare(&a, &b)
+            };
+            assert_eq!(cmp("1", "2").unwrap(), Ordering::Less);
+            assert_eq!(cmp("2.5", "2").unwrap(), Ordering::Greater);
+            assert_eq!(cmp("\"b\"", "\"a\"").unwrap(), Ordering::Greater);
+            assert_eq!(cmp("[ 1 2 ]", "[ 1 2 ]").unwrap(), Ordering::Equal);
+            assert_eq!(cmp("[ 1 2 ]", "[ 1 3 ]").unwrap(), Ordering::Less);
+            assert!(cmp("{ }", "{ }").is_err());
         })
         .unwrap();
     }
@@ -2846,6 +3996,126 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    #[cfg(nix_at_least = "2.26")]
+    fn eval_state_builder_lookup_path_temp_dir() {
+        gc_registering_current_thread(|| {
+            let files: HashMap<std::path::PathBuf, Vec<u8>> = HashMap::from([
+                (
+                    "default.nix".into(),
+                    b"{ a = import ./lib/a.nix; data = builtins.readFile ./data.txt; lib = builtins.attrNames (builtins.readDir ./lib); }".to_vec(),
+                ),
+                ("lib/a.nix".into(), b"1 + 1".to_vec()),
+                ("data.txt".into(), b"hello".to_vec()),
+            ]);
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let builder = EvalStateBuilder::new(store)
+                .unwrap()
+                .lookup_path_temp_dir("project", files)
+                .unwrap();
+            let mut es = builder.build().unwrap();
+            drop(builder);
+            let v = es.eval_from_string("import <project>", "<test>").unwrap();
+            let printed = es
+                .print_value(&v, &crate::print::PrintOptions::default())
+                .unwrap();
+            assert_eq!(printed, "{ a = 2; data = \"hello\"; lib = [ \"a.nix\" ]; }");
+
+            // Reloading keeps the files
+            let mut es = es.reload().unwrap();
+            let v = es.eval_from_string("(import <project>).a", "<test>").unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(nix_at_least = "2.26")]
+    fn eval_state_builder_lookup_path_temp_dir_invalid_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            for path in ["../escape.nix", "/absolute.nix", ""] {
+                assert!(EvalStateBuilder::new(store.clone())
+                    .unwrap()
+                    .lookup_path_temp_dir("project", [(path, "")])
+                    .is_err());
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_try_force() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let f = es
+                .eval_from_string(
+                    r#"
+                    kind:
+                      if kind == "throw" then throw "boom"
+                      else if kind == "assert" then assert kind == "something else"; 1
+                      else if kind == "abort" then abort "fatal"
+                      else if kind == "recurse" then let r = n: 1 + r n; in r 0
+                      else 42
+                    "#,
+                    "<test>",
+                )
+                .unwrap();
+            let thunk = |es: &mut EvalState, kind: &str| {
+                let kind = es.new_value_str(kind).unwrap();
+                es.new_value_apply(&f, &kind).unwrap()
+            };
+
+            let v = thunk(&mut es, "ok");
+            es.try_force(&v).unwrap().unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 42);
+
+            let v = thunk(&mut es, "throw");
+            let err = es.try_force(&v).unwrap().unwrap_err();
+            assert!(err.to_string().contains("boom"), "{}", err);
+
+            let v = thunk(&mut es, "assert");
+            let err = es.try_force(&v).unwrap().unwrap_err();
+            assert!(err.to_string().contains("assertion"), "{}", err);
+
+            let v = thunk(&mut es, "abort");
+            let err = es.try_force(&v).unwrap_err();
+            assert!(err.to_string().contains("fatal"), "{}", err);
+
+            let v = thunk(&mut es, "recurse");
+            assert!(es.try_force(&v).is_err());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_err_name() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let f = es
+                .eval_from_string(
+                    r#"kind: if kind == "throw" then throw "x" else assert false; 1"#,
+                    "<test>",
+                )
+                .unwrap();
+            for (kind, expected) in [
+                ("throw", "nix::ThrowError"),
+                ("assert", "nix::AssertionError"),
+            ] {
+                let kind = es.new_value_str(kind).unwrap();
+                let v = es.new_value_apply(&f, &kind).unwrap();
+                unsafe {
+                    raw::value_force(es.context.ptr(), es.eval_state.as_ptr(), v.raw_ptr());
+                }
+                assert_eq!(es.context.err_name().as_deref(), Some(expected));
+                assert!(es.context.check_err_and_clear().is_err());
+            }
+        })
+        .unwrap();
+    }
+
     #[test]
     #[cfg(nix_at_least = "2.34.0pre")]
     fn eval_state_primop_recoverable_error() {
@@ -0,0 +1,33 @@
+//! Information about the arguments of Nix [functions](https://nix.dev/manual/nix/latest/language/syntax.html#functions).
+//!
+//! See [`EvalState::function_info`][`crate::eval_state::EvalState::function_info`].
+
+/// What kind of argument a Nix function expects, as returned by [`EvalState::function_info`][`crate::eval_state::EvalState::function_info`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FunctionInfo {
+    /// A lambda with a plain argument, such as `x: x`.
+    Lambda {
+        /// The name of the argument.
+        arg: String,
+    },
+    /// A lambda with an attribute set pattern, such as `{ a, b ? 1, ... }: a + b`.
+    Pattern {
+        /// The attributes of the pattern, in alphabetical order.
+        formals: Vec<Formal>,
+        /// Whether the pattern ends in `...`, so that it accepts other attributes as well.
+        ellipsis: bool,
+    },
+    /// A builtin or a primop created with [`PrimOp::new`][`crate::primop::PrimOp::new`], possibly partially applied.
+    ///
+    /// The Nix C API does not provide the name and remaining arity of a primop value.
+    PrimOp,
+}
+
+/// An attribute of a [function pattern][`FunctionInfo::Pattern`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Formal {
+    /// The name of the attribute.
+    pub name: String,
+    /// Whether the attribute has a default value, as in `{ b ? 1 }: ...`, so that it may be omitted.
+    pub has_default: bool,
+}
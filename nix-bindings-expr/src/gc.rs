@@ -0,0 +1,253 @@
+//! # Garbage Collector Statistics and Tuning
+//!
+//! Nix values live in the heap of the [Boehm-Demers-Weiser garbage collector](https://www.hboehm.info/gc/),
+//! which is shared by all [`EvalState`][`crate::eval_state::EvalState`]s in the process.
+//! This module reports on that heap, and configures it.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{gc_now, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::gc::gc_stats;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! gc_now();
+//! let stats = gc_stats()?;
+//! println!("heap: {} bytes, of which {} free", stats.heap_size, stats.free_bytes);
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::eval_state::init;
+use anyhow::{bail, Result};
+use nix_bindings_bdwgc_sys as gc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+
+/// A snapshot of the garbage collector's counters, returned by [`gc_stats`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GcStats {
+    /// The size of the heap in bytes, including free space.
+    pub heap_size: usize,
+    /// The number of free bytes in the heap.
+    pub free_bytes: usize,
+    /// The number of bytes allocated since the last collection.
+    pub bytes_since_gc: usize,
+    /// The number of collections since the process started.
+    pub collections: usize,
+    /// How long the last collection took, or [`None`] if no collection has finished since [`init`].
+    pub last_pause: Option<Duration>,
+    /// The number of bytes allocated since the process started, like `gc.totalBytes` in `NIX_SHOW_STATS`.
+    pub total_bytes: usize,
+    /// The user and system CPU time used by the process, like `cpuTime` in `NIX_SHOW_STATS`.
+    pub cpu_time: Duration,
+}
+
+/// Returns the current garbage collector counters.
+///
+/// The counters are those of the whole process, so they include evaluation in other threads and
+/// other [`EvalState`][`crate::eval_state::EvalState`]s. Subtract the counters of two calls to
+/// measure an evaluation.
+#[doc(alias = "GC_get_heap_size")]
+#[doc(alias = "heap_size")]
+#[doc(alias = "memory")]
+#[doc(alias = "NIX_SHOW_STATS")]
+#[doc(alias = "statistics")]
+pub fn gc_stats() -> Result<GcStats> {
+    init()?;
+    let last_pause = LAST_PAUSE_NANOS.load(Ordering::Relaxed);
+    let cpu_time = process_cpu_time()?;
+    unsafe {
+        Ok(GcStats {
+            heap_size: gc::GC_get_heap_size(),
+            free_bytes: gc::GC_get_free_bytes(),
+            bytes_since_gc: gc::GC_get_bytes_since_gc(),
+            collections: gc::GC_get_gc_no() as usize,
+            last_pause: (last_pause != NO_PAUSE).then(|| Duration::from_nanos(last_pause)),
+            total_bytes: gc::GC_get_total_bytes(),
+            cpu_time,
+        })
+    }
+}
+
+fn process_cpu_time() -> Result<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        bail!("getrusage failed: {}", std::io::Error::last_os_error());
+    }
+    let usage = unsafe { usage.assume_init() };
+    let timeval = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    Ok(timeval(usage.ru_utime) + timeval(usage.ru_stime))
+}
+
+/// Grows the heap to at least `bytes`, so that a large evaluation does not start with many small collections.
+///
+/// This is the programmatic equivalent of the `GC_INITIAL_HEAP_SIZE` environment variable.
+#[doc(alias = "GC_expand_hp")]
+#[doc(alias = "GC_INITIAL_HEAP_SIZE")]
+pub fn set_initial_heap_size(bytes: usize) -> Result<()> {
+    init()?;
+    let current = unsafe { gc::GC_get_heap_size() };
+    if bytes > current && unsafe { gc::GC_expand_hp(bytes - current) } == 0 {
+        bail!(
+            "could not grow the garbage collector heap to {} bytes",
+            bytes
+        );
+    }
+    Ok(())
+}
+
+/// Limits the heap to `bytes`, or removes the limit if `bytes` is 0.
+///
+/// Allocations that would exceed the limit cause an out-of-memory error in the evaluator.
+#[doc(alias = "GC_set_max_heap_size")]
+#[doc(alias = "GC_MAXIMUM_HEAP_SIZE")]
+pub fn set_max_heap_size(bytes: usize) -> Result<()> {
+    init()?;
+    unsafe { gc::GC_set_max_heap_size(bytes as _) };
+    Ok(())
+}
+
+/// Switches the collector to incremental mode, which splits collections into smaller pauses.
+///
+/// Incremental mode can not be switched off again.
+#[doc(alias = "GC_enable_incremental")]
+pub fn enable_incremental() -> Result<()> {
+    init()?;
+    unsafe { gc::GC_enable_incremental() };
+    Ok(())
+}
+
+/// Returns whether the collector is in [incremental mode][`enable_incremental`].
+#[doc(alias = "GC_is_incremental_mode")]
+pub fn is_incremental() -> Result<bool> {
+    init()?;
+    Ok(unsafe { gc::GC_is_incremental_mode() } != 0)
+}
+
+/// Sets the number of threads that mark in parallel during a collection. `1` disables parallel marking.
+///
+/// Must be called before the collector is initialized, i.e. before anything else in this crate, including [`init`].
+/// Returns an [`Err`] otherwise.
+#[doc(alias = "GC_set_markers_count")]
+#[doc(alias = "GC_MARKERS")]
+pub fn set_parallel_markers(count: u32) -> Result<()> {
+    if count == 0 {
+        bail!("set_parallel_markers: the count must be at least 1");
+    }
+    if unsafe { gc::GC_is_init_called() } != 0 {
+        bail!("set_parallel_markers: the garbage collector is already initialized");
+    }
+    unsafe { gc::GC_set_markers_count(count) };
+    Ok(())
+}
+
+/// Returns whether the collector marks with multiple threads.
+#[doc(alias = "GC_get_parallel")]
+pub fn is_parallel() -> Result<bool> {
+    init()?;
+    Ok(unsafe { gc::GC_get_parallel() } != 0)
+}
+
+/// [`LAST_PAUSE_NANOS`] before the first collection has finished.
+const NO_PAUSE: u64 = u64::MAX;
+
+/// The reference point for [`COLLECTION_START_NANOS`], so that an [`Instant`] fits in an atomic.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+static COLLECTION_START_NANOS: AtomicU64 = AtomicU64::new(0);
+static LAST_PAUSE_NANOS: AtomicU64 = AtomicU64::new(NO_PAUSE);
+/// The collection event handler that was installed before ours, if any.
+static PREVIOUS_HANDLER: OnceLock<gc::GC_on_collection_event_proc> = OnceLock::new();
+
+fn nanos_since_epoch() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
+}
+
+unsafe extern "C" fn on_collection_event(event: gc::GC_EventType) {
+    match event {
+        gc::GC_EventType_GC_EVENT_START => {
+            COLLECTION_START_NANOS.store(nanos_since_epoch(), Ordering::Relaxed);
+        }
+        gc::GC_EventType_GC_EVENT_END => {
+            let start = COLLECTION_START_NANOS.load(Ordering::Relaxed);
+            LAST_PAUSE_NANOS.store(nanos_since_epoch().saturating_sub(start), Ordering::Relaxed);
+        }
+        _ => {}
+    }
+    if let Some(Some(previous)) = PREVIOUS_HANDLER.get() {
+        previous(event);
+    }
+}
+
+/// Starts measuring collection times for [`GcStats::last_pause`]. Called once by [`init`].
+pub(crate) fn install_collection_event_handler() {
+    LazyLock::force(&EPOCH);
+    PREVIOUS_HANDLER.get_or_init(|| unsafe {
+        let previous = gc::GC_get_on_collection_event();
+        gc::GC_set_on_collection_event(Some(on_collection_event));
+        previous
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use crate::eval_state::{gc_now, gc_register_my_thread, EvalState};
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    #[test]
+    fn gc_stats_after_collection() {
+        let _guard = gc_register_my_thread().unwrap();
+        let before = gc_stats().unwrap();
+        gc_now();
+        let after = gc_stats().unwrap();
+        assert!(after.collections > before.collections);
+        assert!(after.heap_size > 0);
+        assert!(after.free_bytes <= after.heap_size);
+        assert!(after.last_pause.is_some());
+        assert!(after.total_bytes >= before.total_bytes);
+        assert!(after.cpu_time >= before.cpu_time);
+    }
+
+    #[test]
+    fn gc_stats_measure_evaluation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open(None, HashMap::new()).unwrap();
+            let mut es = EvalState::new(store, []).unwrap();
+            let before = gc_stats().unwrap();
+            let v = es
+                .eval_from_string(
+                    "builtins.foldl' (a: b: a + b) 0 (builtins.genList (x: x) 100000)",
+                    "<test>",
+                )
+                .unwrap();
+            es.force(&v).unwrap();
+            let after = gc_stats().unwrap();
+            assert!(after.total_bytes > before.total_bytes);
+            assert!(after.cpu_time > before.cpu_time);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn gc_set_initial_heap_size() {
+        let _guard = gc_register_my_thread().unwrap();
+        let size = gc_stats().unwrap().heap_size + 1024 * 1024;
+        set_initial_heap_size(size).unwrap();
+        assert!(gc_stats().unwrap().heap_size >= size);
+        // Shrinking is not possible, and not an error
+        set_initial_heap_size(0).unwrap();
+    }
+
+    #[test]
+    fn gc_set_parallel_markers_after_init() {
+        init().unwrap();
+        assert!(set_parallel_markers(1).is_err());
+        assert!(set_parallel_markers(0).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! # Controlling Import From Derivation
+//!
+//! An [`IfdPolicy`] calls a Rust function whenever evaluated code wants to read the output of a
+//! derivation, known as [import from derivation](https://nix.dev/manual/nix/latest/language/import-from-derivation.html) (IFD).
+//! The function can allow the build, deny it with an error, or substitute a path that is read instead,
+//! for example to enforce "no IFD" in CI with a helpful message.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::access::Decision;
+//! # use nix_bindings_expr::ifd::IfdPolicy;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+//! let policy = IfdPolicy::new(&mut es, |request| {
+//!     Decision::Deny(format!("no IFD in CI; {} needs {:?}", request.path, request.derivations))
+//! })?;
+//! let err = policy
+//!     .eval_from_string(
+//!         &mut es,
+//!         r#"import (derivation { name = "gen"; builder = "/bin/sh"; system = "x86_64-linux"; })"#,
+//!         "<example>",
+//!     )
+//!     .unwrap_err();
+//! assert!(err.to_string().contains("no IFD in CI"));
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## How it works
+//!
+//! The policy evaluates code with [`ScopedOverrides`], overriding `import`, `scopedImport`,
+//! `readFile`, `readDir` and `pathExists`.
+//! These check whether their argument has [string context](https://nix.dev/manual/nix/latest/language/string-context.html)
+//! that refers to derivation outputs, which is what makes the evaluator build them.
+//! See [`ScopedOverrides`] for the limitations.
+
+use crate::access::{Access, Decision};
+use crate::eval_state::EvalState;
+use crate::primop::{PrimOp, PrimOpMeta};
+use crate::scoped::ScopedOverrides;
+use anyhow::Result;
+use cstr::cstr;
+use std::ops::Deref;
+
+/// A read of a path that requires building derivations first.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IfdRequest {
+    /// How evaluation wants to read the path.
+    pub access: Access,
+    /// The path, usually an output path of one of the [`derivations`][`Self::derivations`].
+    pub path: String,
+    /// The store paths of the derivations (`.drv` files) that would be built.
+    pub derivations: Vec<String>,
+}
+
+/// The hooks for [`ScopedOverrides`], given the checking primop.
+///
+/// `check` is only called for arguments whose context contains derivation outputs, and returns
+/// an empty string to allow the build, or the path to read instead.
+const HOOKS_EXPR: &str = r#"
+check:
+let
+  checked = kind: p:
+    let
+      context = if builtins.isPath p then { } else builtins.getContext (toString p);
+      derivations = builtins.filter
+        (name: context.${name} ? outputs || context.${name} ? allOutputs)
+        (builtins.attrNames context);
+      substitute = check kind (builtins.unsafeDiscardStringContext (toString p)) derivations;
+    in
+    if derivations == [ ] || substitute == "" then p else /. + substitute;
+in
+{
+  importPath = checked "import";
+  builtins = {
+    readFile = p: builtins.readFile (checked "readFile" p);
+    readDir = p: builtins.readDir (checked "readDir" p);
+    pathExists = p: builtins.pathExists (checked "pathExists" p);
+  };
+}
+"#;
+
+/// Decides on the imports from derivation of the code that it evaluates.
+///
+/// See the [module documentation](self) for an example and limitations.
+pub struct IfdPolicy {
+    overrides: ScopedOverrides,
+}
+
+impl IfdPolicy {
+    /// Creates a policy for evaluating with `eval_state`, which calls `decide` for each read that requires a build.
+    ///
+    /// [`Decision::Redirect`] substitutes the given absolute path for the derivation output.
+    pub fn new(
+        eval_state: &mut EvalState,
+        decide: impl Fn(&IfdRequest) -> Decision + 'static,
+    ) -> Result<Self> {
+        let primop = PrimOp::new(
+            eval_state,
+            PrimOpMeta {
+                name: cstr!("checkImportFromDerivation"),
+                doc: cstr!("Checks whether derivations may be built to read a path, and returns the path to read instead, or an empty string."),
+                args: [cstr!("kind"), cstr!("path"), cstr!("derivations")],
+            },
+            Box::new(move |es, [kind, path, derivations]| {
+                let access = Access::from_kind(&es.require_string(kind)?)?;
+                let path = es.require_string(path)?;
+                let mut drvs = Vec::new();
+                for i in 0..es.require_list_size(derivations)? {
+                    if let Some(drv) = es.require_list_select_idx_strict(derivations, i)? {
+                        drvs.push(es.require_string(&drv)?);
+                    }
+                }
+                let request = IfdRequest {
+                    access,
+                    path,
+                    derivations: drvs,
+                };
+                decide(&request).into_value(
+                    es,
+                    &format!("import from derivation of '{}'", request.path),
+                )
+            }),
+        )?;
+        let overrides = ScopedOverrides::new(eval_state, HOOKS_EXPR, primop)?;
+        Ok(IfdPolicy { overrides })
+    }
+}
+
+/// Provides [`ScopedOverrides::import`] and [`ScopedOverrides::eval_from_string`] for evaluating under the policy.
+impl Deref for IfdPolicy {
+    type Target = ScopedOverrides;
+
+    fn deref(&self) -> &ScopedOverrides {
+        &self.overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use nix_bindings_store::store::Store;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    const GENERATED: &str = r#"
+      let
+        gen = derivation { name = "gen"; builder = "/bin/sh"; system = "x86_64-linux"; };
+      in
+      {
+        text = builtins.readFile "${gen}/data.txt";
+        plain = builtins.readFile ./data.txt;
+      }
+    "#;
+
+    #[test]
+    fn ifd_deny_and_substitute() {
+        gc_registering_current_thread(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let d = dir.path().to_str().unwrap().to_string();
+            std::fs::write(dir.path().join("data.txt"), "plain").unwrap();
+            std::fs::write(dir.path().join("default.nix"), GENERATED).unwrap();
+            std::fs::write(dir.path().join("prebuilt.txt"), "prebuilt").unwrap();
+
+            let requests = Rc::new(RefCell::new(Vec::new()));
+            let recorded = requests.clone();
+            let prebuilt = format!("{d}/prebuilt.txt");
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let substitute = IfdPolicy::new(&mut es, move |request| {
+                recorded.borrow_mut().push(request.clone());
+                Decision::Redirect(prebuilt.clone())
+            })
+            .unwrap();
+            let v = substitute
+                .import(&mut es, &format!("{d}/default.nix"))
+                .unwrap();
+            let plain = es.require_attrs_select(&v, "plain").unwrap();
+            assert_eq!(es.require_string(&plain).unwrap(), "plain");
+            assert!(requests.borrow().is_empty());
+            let text = es.require_attrs_select(&v, "text").unwrap();
+            assert_eq!(es.require_string(&text).unwrap(), "prebuilt");
+            let requests = requests.borrow();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].access, Access::ReadFile);
+            assert!(requests[0].path.ends_with("-gen/data.txt"));
+            assert_eq!(requests[0].derivations.len(), 1);
+            assert!(requests[0].derivations[0].ends_with("-gen.drv"));
+
+            let deny =
+                IfdPolicy::new(&mut es, |_| Decision::Deny("no IFD here".to_string())).unwrap();
+            let v = deny.import(&mut es, &format!("{d}/default.nix")).unwrap();
+            let err = es.require_attrs_select(&v, "text").unwrap_err();
+            assert!(err.to_string().contains("no IFD here"), "{}", err);
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! # Tracking the Inputs of an Evaluation
+//!
+//! An [`InputTracker`] records the files, directories, environment variables and
+//! lookup path entries that an evaluation reads, for example to watch them for changes,
+//! or to key an external evaluation cache.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::inputs::{Input, InputTracker};
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # use std::io::Write as _;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! # let mut file = tempfile::NamedTempFile::new()?;
+//! # writeln!(file, "{{ home = builtins.getEnv \"HOME\"; }}")?;
+//! # let file = file.path().to_str().unwrap();
+//! let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+//! let tracker = InputTracker::new(&mut es)?;
+//! let v = tracker.import(&mut es, file)?;
+//! es.require_attrs_select(&v, "home")?;
+//! assert_eq!(
+//!     tracker.take(),
+//!     vec![Input::Import(file.to_string()), Input::GetEnv("HOME".to_string())]
+//! );
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## How it works
+//!
+//! The tracker evaluates code with [`ScopedOverrides`], overriding `import`, `scopedImport`,
+//! `<...>` lookups (`__findFile`), and the `builtins` functions that read inputs. See there
+//! for the limitations.
+
+use crate::eval_state::EvalState;
+use crate::primop::{PrimOp, PrimOpMeta};
+use crate::scoped::ScopedOverrides;
+use anyhow::{bail, Result};
+use cstr::cstr;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// Something that an evaluation read.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Input {
+    /// A file or directory that was imported with `import` or `scopedImport`, including the
+    /// file passed to [`ScopedOverrides::import`].
+    Import(String),
+    /// A file that was read with `builtins.readFile`.
+    ReadFile(String),
+    /// A directory that was listed with `builtins.readDir`.
+    ReadDir(String),
+    /// A path whose existence was checked with `builtins.pathExists`.
+    PathExists(String),
+    /// An environment variable that was read with `builtins.getEnv`.
+    GetEnv(String),
+    /// A lookup path entry, as in `<nixpkgs>`, and the path it resolved to.
+    LookupPath {
+        /// The name between the angle brackets, e.g. `nixpkgs`.
+        name: String,
+        /// The path that the name resolved to.
+        path: String,
+    },
+}
+
+/// The accesses recorded so far, in order, without duplicates.
+#[derive(Default)]
+struct Records {
+    inputs: Vec<Input>,
+    seen: HashSet<Input>,
+}
+
+/// Records the [`Input`]s read by the code that it evaluates.
+///
+/// See the [module documentation](self) for an example and limitations.
+pub struct InputTracker {
+    records: Rc<RefCell<Records>>,
+    overrides: ScopedOverrides,
+}
+
+/// The hooks for [`ScopedOverrides`], given the recording primop.
+const HOOKS_EXPR: &str = r#"
+record:
+let
+  recorded = kind: p: record kind (toString p) p;
+  findFile = nixPath: name: record "lookupPath" name (builtins.findFile nixPath name);
+in
+{
+  importPath = recorded "import";
+  toplevel.__findFile = findFile;
+  builtins = {
+    inherit findFile;
+    readFile = p: builtins.readFile (recorded "readFile" p);
+    readDir = p: builtins.readDir (recorded "readDir" p);
+    pathExists = p: builtins.pathExists (recorded "pathExists" p);
+    getEnv = name: builtins.getEnv (record "getEnv" name name);
+  };
+}
+"#;
+
+impl InputTracker {
+    /// Creates a tracker for evaluating with `eval_state`.
+    pub fn new(eval_state: &mut EvalState) -> Result<Self> {
+        let records = Rc::new(RefCell::new(Records::default()));
+        let primop_records = records.clone();
+        let primop = PrimOp::new(
+            eval_state,
+            PrimOpMeta {
+                name: cstr!("recordInput"),
+                doc: cstr!("Records that an input was read, and returns the value unchanged."),
+                args: [cstr!("kind"), cstr!("key"), cstr!("value")],
+            },
+            Box::new(move |es, [kind, key, value]| {
+                let kind = es.require_string(kind)?;
+                let key = es.require_string(key)?;
+                let input = match kind.as_str() {
+                    "import" => Input::Import(key),
+                    "readFile" => Input::ReadFile(key),
+                    "readDir" => Input::ReadDir(key),
+                    "pathExists" => Input::PathExists(key),
+                    "getEnv" => Input::GetEnv(key),
+                    "lookupPath" => {
+                        let path = es.call_builtin("toString", std::slice::from_ref(value))?;
+                        Input::LookupPath {
+                            name: key,
+                            path: es.require_string(&path)?,
+                        }
+                    }
+                    _ => bail!("recordInput: unknown input kind `{}`", kind),
+                };
+                let mut records = primop_records.borrow_mut();
+                if records.seen.insert(input.clone()) {
+                    records.inputs.push(input);
+                }
+                Ok(value.clone())
+            }),
+        )?;
+        let overrides = ScopedOverrides::new(eval_state, HOOKS_EXPR, primop)?;
+        Ok(InputTracker { records, overrides })
+    }
+
+    /// Returns the inputs that were read since the tracker was created or since the last call to `take`,
+    /// in the order in which they were first read.
+    #[doc(alias = "finish")]
+    #[doc(alias = "drain")]
+    pub fn take(&self) -> Vec<Input> {
+        let mut records = self.records.borrow_mut();
+        records.seen.clear();
+        std::mem::take(&mut records.inputs)
+    }
+}
+
+/// Provides [`ScopedOverrides::import`] and [`ScopedOverrides::eval_from_string`] for evaluating with tracking.
+impl Deref for InputTracker {
+    type Target = ScopedOverrides;
+
+    fn deref(&self) -> &ScopedOverrides {
+        &self.overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use crate::eval_state::EvalStateBuilder;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    #[test]
+    fn inputs_nested_imports_and_reads() {
+        gc_registering_current_thread(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let d = dir.path().to_str().unwrap();
+            std::fs::write(
+                dir.path().join("default.nix"),
+                "{ a = import ./a.nix; exists = builtins.pathExists ./missing; }",
+            )
+            .unwrap();
+            std::fs::write(
+                dir.path().join("a.nix"),
+                "builtins.readFile ./data.txt + toString (builtins.attrNames (builtins.readDir ./sub))",
+            )
+            .unwrap();
+            std::fs::write(dir.path().join("data.txt"), "data").unwrap();
+            std::fs::create_dir(dir.path().join("sub")).unwrap();
+            std::fs::write(dir.path().join("sub/x"), "").unwrap();
+
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let tracker = InputTracker::new(&mut es).unwrap();
+            let v = tracker
+                .import(&mut es, &format!("{d}/default.nix"))
+                .unwrap();
+            assert_eq!(
+                tracker.take(),
+                vec![Input::Import(format!("{d}/default.nix"))]
+            );
+
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            assert_eq!(es.require_string(&a).unwrap(), "datax");
+            let exists = es.require_attrs_select(&v, "exists").unwrap();
+            assert!(!es.require_bool(&exists).unwrap());
+            assert_eq!(
+                tracker.take(),
+                vec![
+                    Input::Import(format!("{d}/a.nix")),
+                    Input::ReadFile(format!("{d}/data.txt")),
+                    Input::ReadDir(format!("{d}/sub")),
+                    Input::PathExists(format!("{d}/missing")),
+                ]
+            );
+            assert!(tracker.take().is_empty());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn inputs_lookup_path_and_env() {
+        gc_registering_current_thread(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let d = dir.path().to_str().unwrap();
+            std::fs::write(dir.path().join("default.nix"), "42").unwrap();
+
+            let mut es = EvalStateBuilder::new(Store::open(None, HashMap::new()).unwrap())
+                .unwrap()
+                .lookup_path([format!("answer={d}").as_str()])
+                .unwrap()
+                .build()
+                .unwrap();
+            let tracker = InputTracker::new(&mut es).unwrap();
+            let v = tracker
+                .eval_from_string(
+                    &mut es,
+                    "[ (import <answer>) (builtins.getEnv \"NIX_BINDINGS_TEST_UNSET\") (builtins.getEnv \"NIX_BINDINGS_TEST_UNSET\") ]",
+                    "<test>",
+                )
+                .unwrap();
+            let answer = es.require_list_select_idx_strict(&v, 0).unwrap().unwrap();
+            assert_eq!(es.require_int(&answer).unwrap(), 42);
+            es.require_list_select_idx_strict(&v, 1).unwrap();
+            es.require_list_select_idx_strict(&v, 2).unwrap();
+            assert_eq!(
+                tracker.take(),
+                vec![
+                    Input::LookupPath {
+                        name: "answer".to_string(),
+                        path: d.to_string(),
+                    },
+                    Input::Import(d.to_string()),
+                    Input::GetEnv("NIX_BINDINGS_TEST_UNSET".to_string()),
+                ]
+            );
+        })
+        .unwrap();
+    }
+}
@@ -1,3 +1,21 @@
+pub mod access;
+#[cfg(all(feature = "tokio", nix_at_least = "2.26"))]
+pub mod async_eval;
+pub mod branded;
+pub mod derivations;
 pub mod eval_state;
+pub mod function;
+pub mod gc;
+pub mod ifd;
+pub mod inputs;
+#[cfg(nix_at_least = "2.26")]
+pub mod pool;
 pub mod primop;
+pub mod print;
+pub mod scoped;
+pub mod session;
+#[cfg(nix_at_least = "2.26")]
+pub mod shared;
+pub mod thread;
+pub mod trace;
 pub mod value;
@@ -0,0 +1,361 @@
+//! # Parallel Evaluation with a Pool of `EvalState`s
+//!
+//! The Nix evaluator is single threaded, but independent expressions can be
+//! evaluated in parallel by separate [`EvalState`]s. An [`EvalStatePool`] owns a
+//! number of worker threads, each registered with the garbage collector and each
+//! with its own `EvalState`, and distributes jobs over them.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalStateBuilder, test_init};
+//! # use nix_bindings_expr::pool::{EvalJob, EvalStatePool};
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # use std::io::Write as _;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let mut file = tempfile::NamedTempFile::new()?;
+//! # writeln!(file, "{{ machines = {{ a.port = 1; b.port = 2; }}; }}")?;
+//! let pool = EvalStatePool::new(4, 16, || {
+//!     EvalStateBuilder::new(Store::open(None, HashMap::new())?)
+//! })?;
+//!
+//! let jobs = ["a", "b"].map(|name| EvalJob::new(file.path(), ["machines", name]));
+//! let results = pool.evaluate_all(jobs);
+//! assert_eq!(results[0].as_ref().unwrap(), r#"{"port":1}"#);
+//! assert_eq!(results[1].as_ref().unwrap(), r#"{"port":2}"#);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::eval_state::{gc_register_my_thread, EvalState, EvalStateBuilder};
+use crate::value::ValueType;
+use anyhow::{bail, format_err, Context as _, Result};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// A unit of work for a worker thread.
+pub(crate) type Task = Box<dyn FnOnce(&mut EvalState) + Send>;
+
+/// A request to evaluate an attribute of a Nix file and return it as JSON.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EvalJob {
+    /// The file to [import](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-import).
+    ///
+    /// If the file evaluates to a function, it is called with an empty attribute set, so that its
+    /// arguments must all have defaults, like with `nix-build`.
+    pub file: PathBuf,
+    /// The attribute path to select from the file, e.g. `["machines", "web"]`. May be empty.
+    pub attr_path: Vec<String>,
+}
+
+impl EvalJob {
+    /// Creates a job that evaluates `attr_path` in `file`.
+    pub fn new<S: Into<String>>(
+        file: impl AsRef<Path>,
+        attr_path: impl IntoIterator<Item = S>,
+    ) -> Self {
+        EvalJob {
+            file: file.as_ref().to_owned(),
+            attr_path: attr_path.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Runs the job, returning the value as JSON.
+    pub(crate) fn run(&self, es: &mut EvalState) -> Result<String> {
+        self.run_inner(es).with_context(|| {
+            format!(
+                "while evaluating `{}` in {}",
+                self.attr_path.join("."),
+                self.file.display()
+            )
+        })
+    }
+
+    fn run_inner(&self, es: &mut EvalState) -> Result<String> {
+        let file = self
+            .file
+            .to_str()
+            .with_context(|| format!("path is not valid UTF-8: {}", self.file.display()))?;
+        let file = es.new_value_str(file)?;
+        let mut v = es.call_builtin("import", &[file])?;
+        if es.value_type(&v)? == ValueType::Function {
+            let args = es.new_value_attrs(Vec::new())?;
+            v = es.call(v, args)?;
+        }
+        for (i, name) in self.attr_path.iter().enumerate() {
+            v = es.require_attrs_select(&v, name).with_context(|| {
+                format!(
+                    "while selecting attribute `{}`",
+                    self.attr_path[..=i].join(".")
+                )
+            })?;
+        }
+        let json = es.call_builtin("toJSON", &[v])?;
+        es.require_string(&json)
+    }
+}
+
+/// The pending result of a job submitted to an [`EvalStatePool`].
+pub struct JobHandle<R> {
+    receiver: Receiver<Result<R>>,
+}
+
+impl<R> JobHandle<R> {
+    /// Blocks until the job has finished, and returns its result.
+    pub fn wait(self) -> Result<R> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(format_err!("evaluation job panicked")))
+    }
+}
+
+/// A fixed number of worker threads, each with its own [`EvalState`].
+///
+/// Jobs are queued in a bounded queue. When the queue is full, submitting a job blocks until a worker
+/// picks up a queued job, so that a large batch of jobs does not build up an unbounded backlog.
+///
+/// Dropping the pool finishes the queued jobs and then stops the workers.
+///
+/// See the [module documentation](self) for an example.
+pub struct EvalStatePool {
+    // Only None during drop.
+    sender: Option<SyncSender<Task>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl EvalStatePool {
+    /// Starts `threads` worker threads, with room for `queue_size` queued jobs.
+    ///
+    /// Each worker builds its [`EvalState`] from an [`EvalStateBuilder`] returned by `builder`, so that all workers share the same configuration.
+    /// Returns an [`Err`] if any of the workers fails to build its `EvalState`.
+    pub fn new<F>(threads: usize, queue_size: usize, builder: F) -> Result<Self>
+    where
+        F: Fn() -> Result<EvalStateBuilder> + Send + Sync + 'static,
+    {
+        if threads == 0 {
+            bail!("EvalStatePool::new: the number of threads must be at least 1");
+        }
+        let builder = Arc::new(builder);
+        let (sender, receiver) = mpsc::sync_channel::<Task>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (started_sender, started_receiver) = mpsc::channel::<Result<()>>();
+
+        let mut pool = EvalStatePool {
+            sender: Some(sender),
+            workers: Vec::with_capacity(threads),
+        };
+        for i in 0..threads {
+            let builder = builder.clone();
+            let receiver = receiver.clone();
+            let started = started_sender.clone();
+            let worker = std::thread::Builder::new()
+                .name(format!("nix-eval-{i}"))
+                .spawn(move || worker(builder.as_ref(), &receiver, started))
+                .context("EvalStatePool::new: could not spawn worker thread")?;
+            pool.workers.push(worker);
+        }
+        drop(started_sender);
+        for _ in 0..threads {
+            started_receiver
+                .recv()
+                .unwrap_or_else(|_| Err(format_err!("worker thread panicked during startup")))
+                .context("EvalStatePool::new: could not start worker")?;
+        }
+        Ok(pool)
+    }
+
+    /// Returns the number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Queues `f` to run on one of the workers' [`EvalState`]s.
+    ///
+    /// Blocks while the queue is full. [`Value`][`crate::value::Value`]s can not be returned, as they belong to the worker's
+    /// `EvalState`; convert them to Rust data within `f` instead.
+    #[doc(alias = "spawn")]
+    #[doc(alias = "execute")]
+    pub fn submit_fn<R, F>(&self, f: F) -> Result<JobHandle<R>>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut EvalState) -> Result<R> + Send + 'static,
+    {
+        let (result_sender, receiver) = mpsc::sync_channel(1);
+        self.submit_task(Box::new(move |es| {
+            // The receiver may have been dropped; the result is not needed then.
+            let _ = result_sender.send(f(es));
+        }))?;
+        Ok(JobHandle { receiver })
+    }
+
+    /// Returns whether the current thread is one of the pool's worker threads.
+    pub(crate) fn is_worker_thread(&self) -> bool {
+        let current = std::thread::current().id();
+        self.workers.iter().any(|w| w.thread().id() == current)
+    }
+
+    /// Queues a task, blocking while the queue is full.
+    pub(crate) fn submit_task(&self, task: Task) -> Result<()> {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(task)
+            .map_err(|_| format_err!("EvalStatePool: all worker threads have stopped"))
+    }
+
+    /// Queues an [`EvalJob`].
+    ///
+    /// Blocks while the queue is full.
+    pub fn submit(&self, job: EvalJob) -> Result<JobHandle<String>> {
+        self.submit_fn(move |es| job.run(es))
+    }
+
+    /// Evaluates all `jobs`, and returns their results in the same order.
+    ///
+    /// A failing job does not affect the other jobs.
+    pub fn evaluate_all(&self, jobs: impl IntoIterator<Item = EvalJob>) -> Vec<Result<String>> {
+        let handles: Vec<Result<JobHandle<String>>> =
+            jobs.into_iter().map(|job| self.submit(job)).collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.and_then(JobHandle::wait))
+            .collect()
+    }
+}
+
+impl Drop for EvalStatePool {
+    fn drop(&mut self) {
+        // Closing the queue lets the workers exit after the remaining jobs.
+        drop(self.sender.take());
+        let current = std::thread::current().id();
+        for worker in self.workers.drain(..) {
+            // A job may drop the last reference to the pool; its worker exits after the job instead.
+            if worker.thread().id() != current {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+/// The main loop of a worker thread.
+fn worker(
+    builder: &(dyn Fn() -> Result<EvalStateBuilder> + Send + Sync),
+    receiver: &Mutex<Receiver<Task>>,
+    started: mpsc::Sender<Result<()>>,
+) {
+    let guard = match gc_register_my_thread() {
+        Ok(guard) => guard,
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        }
+    };
+    let mut es = match builder().and_then(|b| b.build()) {
+        Ok(es) => es,
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        }
+    };
+    let _ = started.send(Ok(()));
+    drop(started);
+
+    loop {
+        // Only hold the lock while waiting for a task, not while running it.
+        let task = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => break,
+        };
+        let Ok(task) = task else {
+            // The pool was dropped.
+            break;
+        };
+        // A panicking job drops its result sender, which its JobHandle reports as an error.
+        let _ = catch_unwind(AssertUnwindSafe(|| task(&mut es)));
+    }
+    drop(es);
+    drop(guard);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+    use std::io::Write as _;
+
+    fn new_pool(threads: usize, queue_size: usize) -> EvalStatePool {
+        EvalStatePool::new(threads, queue_size, || {
+            EvalStateBuilder::new(Store::open(None, HashMap::new())?)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn pool_evaluate_all() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "{{ targets = builtins.listToAttrs (builtins.genList (i: {{ name = \"t${{toString i}}\"; value = {{ n = i * i; }}; }}) 20); }}"
+        )
+        .unwrap();
+        let pool = new_pool(3, 2);
+        assert_eq!(pool.threads(), 3);
+        let jobs =
+            (0..20).map(|i| EvalJob::new(file.path(), ["targets".to_string(), format!("t{i}")]));
+        let results = pool.evaluate_all(jobs);
+        for (i, r) in results.into_iter().enumerate() {
+            assert_eq!(r.unwrap(), format!("{{\"n\":{}}}", i * i));
+        }
+    }
+
+    #[test]
+    fn pool_per_job_errors() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "{{ ... }}: {{ good = [ 1 ]; bad = throw \"broken\"; }}"
+        )
+        .unwrap();
+        let pool = new_pool(2, 1);
+        let results = pool.evaluate_all([
+            EvalJob::new(file.path(), ["bad"]),
+            EvalJob::new(file.path(), ["good"]),
+            EvalJob::new(file.path(), ["missing"]),
+            EvalJob::new(file.path(), Vec::<String>::new()),
+        ]);
+        let err = results[0].as_ref().unwrap_err();
+        assert!(format!("{err:#}").contains("broken"));
+        assert_eq!(results[1].as_ref().unwrap(), "[1]");
+        let err = results[2].as_ref().unwrap_err();
+        assert!(format!("{err:#}").contains("missing"));
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn pool_submit_fn_and_panic() {
+        let pool = new_pool(1, 1);
+        let h = pool
+            .submit_fn(|_| -> Result<()> { panic!("job panic") })
+            .unwrap();
+        assert!(h.wait().is_err());
+        // The worker survives a panicking job.
+        let h = pool
+            .submit_fn(|es| {
+                let v = es.eval_from_string("6 * 7", "<test>")?;
+                es.require_int(&v)
+            })
+            .unwrap();
+        assert_eq!(h.wait().unwrap(), 42);
+    }
+
+    #[test]
+    fn pool_builder_error() {
+        let r = EvalStatePool::new(2, 1, || bail!("no builder for you"));
+        let err = r.err().unwrap();
+        assert!(format!("{err:#}").contains("no builder for you"));
+    }
+}
@@ -0,0 +1,560 @@
+//! # Printing Values as Nix Syntax
+//!
+//! [`EvalState::print_value`] renders a [`Value`] in the syntax of the Nix language, similar to `nix repl`.
+//! Unlike [`builtins.toJSON`](https://nix.dev/manual/nix/latest/language/builtins.html#builtins-toJSON),
+//! this works for any value, including functions and paths, and it can leave thunks unevaluated.
+//!
+//! Parts of the value that can't be written as Nix syntax are shown with markers:
+//! - `«lambda»` and `«primop»` for functions, including partially applied primops
+//! - `«thunk»` for values that have not been evaluated, when not [forcing][`PrintOptions::force`]
+//! - `«repeated»` for attribute sets and lists that were already printed, e.g. in cyclic structures
+//! - `«error: message»` for attributes and list elements whose evaluation failed
+//! - `«external»` for external values
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::print::PrintOptions;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let store = Store::open(None, HashMap::new())?;
+//! let mut es = EvalState::new(store, [])?;
+//!
+//! let v = es.eval_from_string(r#"{ "a b" = [ 1 "x\n" ]; f = x: x; }"#, "<example>")?;
+//! let s = es.print_value(&v, &PrintOptions::default())?;
+//! assert_eq!(s, r#"{ "a b" = [ 1 "x\n" ]; f = «lambda»; }"#);
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::eval_state::{is_identifier, EvalState};
+use crate::function::FunctionInfo;
+use crate::value::{Value, ValueType};
+use anyhow::Result;
+use nix_bindings_expr_sys as raw;
+use nix_bindings_util::check_call;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::{c_char, c_uint, CStr};
+use std::fmt::Write as _;
+use std::ptr::null;
+
+/// Options for [`EvalState::print_value`].
+#[derive(Clone, Debug)]
+pub struct PrintOptions {
+    /// Whether to [force][`EvalState::force`] the printed values.
+    ///
+    /// When `false`, unevaluated parts are printed as `«thunk»`, and printing does not evaluate anything.
+    ///
+    /// Printing the contents of attribute sets and lists without forcing them requires Nix 2.34 or later.
+    /// With older versions, they are printed as `{ ... }` and `[ ... ]` when this is `false`.
+    ///
+    /// Default: `true`
+    pub force: bool,
+
+    /// The maximum number of nested attribute sets and lists to print.
+    /// Deeper ones are printed as `{ ... }` and `[ ... ]`.
+    ///
+    /// Default: [`usize::MAX`]
+    pub max_depth: usize,
+}
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions {
+            force: true,
+            max_depth: usize::MAX,
+        }
+    }
+}
+
+impl EvalState {
+    /// Renders a value in the syntax of the Nix language.
+    ///
+    /// See the [module documentation][`crate::print`] for the format.
+    ///
+    /// Returns an [`Err`] if forcing the value itself fails.
+    /// Failures in nested values are printed as `«error: message»` instead.
+    #[doc(alias = "show")]
+    #[doc(alias = "pretty_print")]
+    #[doc(alias = "to_nix")]
+    pub fn print_value(&mut self, value: &Value, options: &PrintOptions) -> Result<String> {
+        let mut printer = Printer {
+            eval_state: self,
+            options,
+            seen: HashSet::new(),
+            out: String::new(),
+        };
+        printer.print(value, 0)?;
+        Ok(printer.out)
+    }
+
+    /// Returns an adapter for printing a value with [`std::fmt::Display`], e.g. in `format!("{}", es.display(&v))`.
+    ///
+    /// The value is [forced][`EvalState::force`] and printed like [`print_value`][`Self::print_value`] does,
+    /// up to a nesting depth of [`DISPLAY_MAX_DEPTH`]. Errors are printed as `«error: message»`.
+    ///
+    /// For printing without an `EvalState` and without forcing, use [`Debug`][`std::fmt::Debug`] instead.
+    #[doc(alias = "to_string")]
+    #[doc(alias = "fmt")]
+    pub fn display<'a>(&'a mut self, value: &'a Value) -> ValueDisplay<'a> {
+        self.display_with_options(
+            value,
+            PrintOptions {
+                max_depth: DISPLAY_MAX_DEPTH,
+                ..PrintOptions::default()
+            },
+        )
+    }
+
+    /// Like [`display`][`Self::display`], with custom [`PrintOptions`].
+    pub fn display_with_options<'a>(
+        &'a mut self,
+        value: &'a Value,
+        options: PrintOptions,
+    ) -> ValueDisplay<'a> {
+        ValueDisplay {
+            eval_state: RefCell::new(self),
+            value,
+            options,
+        }
+    }
+}
+
+/// The default depth limit of [`EvalState::display`].
+pub const DISPLAY_MAX_DEPTH: usize = 3;
+
+/// Prints a [`Value`] with [`std::fmt::Display`]. Returned by [`EvalState::display`].
+pub struct ValueDisplay<'a> {
+    // RefCell, because Display::fmt only gets a shared reference.
+    eval_state: RefCell<&'a mut EvalState>,
+    value: &'a Value,
+    options: PrintOptions,
+}
+
+impl std::fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut eval_state = self.eval_state.borrow_mut();
+        match eval_state.print_value(self.value, &self.options) {
+            Ok(s) => f.write_str(&s),
+            Err(e) => write!(f, "«error: {}»", e),
+        }
+    }
+}
+
+struct Printer<'a> {
+    eval_state: &'a mut EvalState,
+    options: &'a PrintOptions,
+    /// Attribute sets and lists that have been printed already.
+    seen: HashSet<*mut raw::Value>,
+    out: String,
+}
+
+impl Printer<'_> {
+    fn print(&mut self, value: &Value, depth: usize) -> Result<()> {
+        let t = match self.eval_state.value_type_unforced(value) {
+            Some(t) => t,
+            None if !self.options.force => {
+                self.out.push_str("«thunk»");
+                return Ok(());
+            }
+            None => self.eval_state.value_type(value)?,
+        };
+        match t {
+            ValueType::Null => self.out.push_str("null"),
+            ValueType::Bool => {
+                let b = self.eval_state.require_bool(value)?;
+                self.out.push_str(if b { "true" } else { "false" });
+            }
+            ValueType::Int => {
+                let i = self.eval_state.require_int(value)?;
+                write!(self.out, "{}", i)?;
+            }
+            ValueType::Float => {
+                let f = unsafe {
+                    check_call!(raw::get_float(
+                        &mut self.eval_state.context,
+                        value.raw_ptr()
+                    ))
+                }?;
+                write!(self.out, "{}", f)?;
+            }
+            ValueType::String => {
+                let s = self.eval_state.require_string(value)?;
+                write_string_literal(&mut self.out, &s);
+            }
+            ValueType::Path => {
+                let p = unsafe {
+                    let p = check_call!(raw::get_path_string(
+                        &mut self.eval_state.context,
+                        value.raw_ptr()
+                    ))?;
+                    CStr::from_ptr(p).to_string_lossy().into_owned()
+                };
+                self.out.push_str(&p);
+            }
+            ValueType::AttrSet => self.print_attrs(value, depth)?,
+            ValueType::List => self.print_list(value, depth)?,
+            ValueType::Function => match self.eval_state.function_info(value)? {
+                FunctionInfo::PrimOp => self.out.push_str("«primop»"),
+                FunctionInfo::Lambda { .. } | FunctionInfo::Pattern { .. } => {
+                    self.out.push_str("«lambda»")
+                }
+            },
+            ValueType::External => self.out.push_str("«external»"),
+            ValueType::Unknown => self.out.push_str("«unknown»"),
+        }
+        Ok(())
+    }
+
+    /// Prints a nested value, or the error that occurred while getting or printing it.
+    fn print_nested(&mut self, value: Result<Value>, depth: usize) -> Result<()> {
+        let r = value.and_then(|value| {
+            let len = self.out.len();
+            self.print(&value, depth)
+                .inspect_err(|_| self.out.truncate(len))
+        });
+        if let Err(e) = r {
+            write!(self.out, "«error: {}»", e)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the contents of the value should be printed, printing a marker otherwise.
+    fn enter(&mut self, value: &Value, depth: usize, elided: &str) -> bool {
+        if depth >= self.options.max_depth
+            || !(self.options.force || cfg!(nix_at_least = "2.34.0pre"))
+        {
+            self.out.push_str(elided);
+            return false;
+        }
+        if !self.seen.insert(unsafe { value.raw_ptr() }) {
+            self.out.push_str("«repeated»");
+            return false;
+        }
+        true
+    }
+
+    fn print_attrs(&mut self, value: &Value, depth: usize) -> Result<()> {
+        if !self.enter(value, depth, "{ ... }") {
+            return Ok(());
+        }
+        let es = &mut *self.eval_state;
+        let n = unsafe { check_call!(raw::get_attrs_size(&mut es.context, value.raw_ptr())) }?;
+        let mut names = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            let name = unsafe {
+                let name_ptr: *const c_char = check_call!(raw::get_attr_name_byidx(
+                    &mut es.context,
+                    value.raw_ptr(),
+                    es.raw_ptr(),
+                    i
+                ))?;
+                CStr::from_ptr(name_ptr).to_string_lossy().into_owned()
+            };
+            names.push((name, i));
+        }
+        names.sort();
+
+        self.out.push('{');
+        for (name, i) in names {
+            self.out.push(' ');
+            if is_identifier(&name) {
+                self.out.push_str(&name);
+            } else {
+                write_string_literal(&mut self.out, &name);
+            }
+            self.out.push_str(" = ");
+            let attr = self.attr_byidx(value, i);
+            self.print_nested(attr, depth + 1)?;
+            self.out.push(';');
+        }
+        self.out.push_str(" }");
+        Ok(())
+    }
+
+    fn print_list(&mut self, value: &Value, depth: usize) -> Result<()> {
+        if !self.enter(value, depth, "[ ... ]") {
+            return Ok(());
+        }
+        let n = self.eval_state.require_list_size(value)?;
+        self.out.push('[');
+        for i in 0..n {
+            self.out.push(' ');
+            let element = self.list_byidx(value, i);
+            self.print_nested(element, depth + 1)?;
+        }
+        self.out.push_str(" ]");
+        Ok(())
+    }
+
+    fn attr_byidx(&mut self, value: &Value, i: c_uint) -> Result<Value> {
+        let es = &mut *self.eval_state;
+        let mut name: *const c_char = null();
+        let v = unsafe {
+            #[cfg(nix_at_least = "2.34.0pre")]
+            if !self.options.force {
+                let v = check_call!(raw::get_attr_byidx_lazy(
+                    &mut es.context,
+                    value.raw_ptr(),
+                    es.raw_ptr(),
+                    i,
+                    &mut name
+                ))?;
+                return Ok(Value::new(v));
+            }
+            check_call!(raw::get_attr_byidx(
+                &mut es.context,
+                value.raw_ptr(),
+                es.raw_ptr(),
+                i,
+                &mut name
+            ))?
+        };
+        Ok(unsafe { Value::new(v) })
+    }
+
+    fn list_byidx(&mut self, value: &Value, i: u32) -> Result<Value> {
+        let es = &mut *self.eval_state;
+        let v = unsafe {
+            #[cfg(nix_at_least = "2.34.0pre")]
+            if !self.options.force {
+                let v = check_call!(raw::get_list_byidx_lazy(
+                    &mut es.context,
+                    value.raw_ptr(),
+                    es.raw_ptr(),
+                    i
+                ))?;
+                return Ok(Value::new(v));
+            }
+            check_call!(raw::get_list_byidx(
+                &mut es.context,
+                value.raw_ptr(),
+                es.raw_ptr(),
+                i
+            ))?
+        };
+        Ok(unsafe { Value::new(v) })
+    }
+}
+
+/// Writes `s` as a Nix string literal, with quotes and escapes.
+pub(crate) fn write_string_literal(out: &mut String, s: &str) {
+    out.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // Prevent interpolation
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::gc_register_my_thread;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    fn print(es: &mut EvalState, expr: &str, options: &PrintOptions) -> String {
+        let v = es.eval_from_string(expr, "<test>").unwrap();
+        es.print_value(&v, options).unwrap()
+    }
+
+    #[test]
+    fn write_string_literal_escapes() {
+        let mut out = String::new();
+        write_string_literal(&mut out, "a\"b\\c\nd\re\tf${g}$h");
+        assert_eq!(out, r#""a\"b\\c\nd\re\tf\${g}$h""#);
+    }
+
+    #[test]
+    fn print_value_scalars() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions::default();
+        assert_eq!(print(&mut es, "null", &options), "null");
+        assert_eq!(print(&mut es, "true", &options), "true");
+        assert_eq!(print(&mut es, "-42", &options), "-42");
+        assert_eq!(print(&mut es, "1.5", &options), "1.5");
+        assert_eq!(print(&mut es, "\"a\\nb\"", &options), "\"a\\nb\"");
+        assert_eq!(print(&mut es, "/foo/bar", &options), "/foo/bar");
+    }
+
+    #[test]
+    fn print_value_structures() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions::default();
+        assert_eq!(print(&mut es, "{ }", &options), "{ }");
+        assert_eq!(print(&mut es, "[ ]", &options), "[ ]");
+        assert_eq!(
+            print(
+                &mut es,
+                "{ b = [ 1 { c = 2; } ]; a = null; \"with space\" = 1; \"if\" = 2; }",
+                &options
+            ),
+            "{ a = null; b = [ 1 { c = 2; } ]; \"if\" = 2; \"with space\" = 1; }"
+        );
+    }
+
+    #[test]
+    fn print_value_functions() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions::default();
+        assert_eq!(
+            print(
+                &mut es,
+                "[ (x: x) ({ a }: a) builtins.map (builtins.map (x: x)) ]",
+                &options
+            ),
+            "[ «lambda» «lambda» «primop» «primop» ]"
+        );
+    }
+
+    #[test]
+    fn print_value_errors() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions::default();
+        let s = print(&mut es, "{ a = throw \"oops\"; b = 1; }", &options);
+        assert!(s.starts_with("{ a = «error: "), "unexpected output: {}", s);
+        assert!(s.contains("oops"), "unexpected output: {}", s);
+        assert!(s.ends_with("»; b = 1; }"), "unexpected output: {}", s);
+
+        // eval_from_string would throw, so apply throw lazily instead
+        let throw = es.builtin("throw").unwrap();
+        let msg = es.new_value_str("top").unwrap();
+        let v = es.new_value_apply(&throw, &msg).unwrap();
+        assert!(es.print_value(&v, &options).is_err());
+    }
+
+    #[test]
+    fn print_value_repeated() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions::default();
+        let s = print(&mut es, "let x = { a = x; }; in x", &options);
+        assert!(s.contains("«repeated»"), "unexpected output: {}", s);
+    }
+
+    #[test]
+    fn print_value_max_depth() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions {
+            max_depth: 1,
+            ..PrintOptions::default()
+        };
+        assert_eq!(
+            print(&mut es, "{ a = { b = 1; }; c = [ 1 ]; d = 2; }", &options),
+            "{ a = { ... }; c = [ ... ]; d = 2; }"
+        );
+        let options = PrintOptions {
+            max_depth: 0,
+            ..PrintOptions::default()
+        };
+        assert_eq!(print(&mut es, "[ 1 ]", &options), "[ ... ]");
+    }
+
+    #[test]
+    fn display_value() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let v = es
+            .eval_from_string("{ a.b.c.d = 1; e = [ \"f\" ]; }", "<test>")
+            .unwrap();
+        assert_eq!(
+            format!("{}", es.display(&v)),
+            "{ a = { b = { c = { ... }; }; }; e = [ \"f\" ]; }"
+        );
+        let throw = es.builtin("throw").unwrap();
+        let msg = es.new_value_str("oops").unwrap();
+        let v = es.new_value_apply(&throw, &msg).unwrap();
+        let s = es.display(&v).to_string();
+        assert!(s.starts_with("«error: "), "unexpected output: {}", s);
+        assert!(s.contains("oops"), "unexpected output: {}", s);
+    }
+
+    #[test]
+    fn debug_value() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let mut debug = |expr: &str| {
+            let v = es.eval_from_string(expr, "<test>").unwrap();
+            format!("{:?}", v)
+        };
+        assert_eq!(debug("42"), "Value(Int(42))");
+        assert_eq!(debug("true"), "Value(Bool(true))");
+        assert_eq!(debug("1.5"), "Value(Float(1.5))");
+        assert_eq!(debug("\"a\\nb\""), "Value(String(\"a\\nb\"))");
+        assert_eq!(debug("/foo"), "Value(Path(\"/foo\"))");
+        assert_eq!(debug("null"), "Value(Null)");
+        assert_eq!(debug("{ a = 1; }"), "Value(AttrSet)");
+        assert_eq!(debug("[ ]"), "Value(List)");
+        assert_eq!(debug("x: x"), "Value(Function)");
+
+        // Debug does not force thunks
+        let v = es
+            .eval_from_string("{ a = throw \"not forced\"; }", "<test>")
+            .unwrap();
+        let f = es.builtin("attrNames").unwrap();
+        let thunk = es.new_value_apply(&f, &v).unwrap();
+        assert_eq!(format!("{:?}", thunk), "Value(Thunk)");
+        assert_eq!(es.value_type_unforced(&thunk), None);
+
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Holder {
+            value: Value,
+        }
+        let holder = Holder {
+            value: es.new_value_int(1).unwrap(),
+        };
+        assert_eq!(format!("{:?}", holder), "Holder { value: Value(Int(1)) }");
+    }
+
+    #[test]
+    fn print_value_no_force() {
+        let _guard = gc_register_my_thread().unwrap();
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let options = PrintOptions {
+            force: false,
+            ..PrintOptions::default()
+        };
+        let v = es
+            .eval_from_string("{ a = 1; b = throw \"no\"; }", "<test>")
+            .unwrap();
+        let s = es.print_value(&v, &options).unwrap();
+        if cfg!(nix_at_least = "2.34.0pre") {
+            assert_eq!(s, "{ a = 1; b = «thunk»; }");
+        } else {
+            assert_eq!(s, "{ ... }");
+        }
+
+        let to_string = es.builtin("toString").unwrap();
+        let thunk = es.new_value_apply(&to_string, &v).unwrap();
+        assert_eq!(es.print_value(&thunk, &options).unwrap(), "«thunk»");
+    }
+}
@@ -0,0 +1,103 @@
+//! # Evaluating with Overridden Builtins
+//!
+//! The Nix C API has no hooks for file accesses, instantiations, traces and the like. The
+//! [`inputs`][`crate::inputs`], [`derivations`][`crate::derivations`], [`ifd`][`crate::ifd`],
+//! [`access`][`crate::access`] and [`trace`][`crate::trace`] modules observe them with
+//! [`ScopedOverrides`] instead, which evaluate code with some of the builtins replaced by Nix
+//! functions that call a Rust primop.
+
+use crate::eval_state::EvalState;
+use crate::primop::PrimOp;
+use crate::value::Value;
+use anyhow::Result;
+
+/// Wraps the hooks in overrides for `import` and `scopedImport` that pass the same overrides
+/// on to the imported files.
+const OVERRIDES_EXPR: &str = r#"
+hooks: primop:
+let
+  h = hooks primop;
+  importPath = h.importPath or (p: p);
+  overrides = (h.toplevel or { }) // {
+    import = p: scopedImport overrides (importPath p);
+    scopedImport = scope: p: scopedImport (overrides // scope) (importPath p);
+    builtins = builtins // (h.builtins or { }) // {
+      inherit (overrides) import scopedImport;
+    };
+  };
+in
+overrides
+"#;
+
+/// Names that are overridden in code evaluated through it, and in all files imported by that code.
+///
+/// Built from a Nix function that takes a primop, and returns the hooks as an attribute set with
+/// the optional attributes:
+///
+/// - `importPath`, a function that is applied to the path of each `import` and `scopedImport`,
+///   and returns the path to import.
+/// - `builtins`, the attributes that replace those of `builtins`.
+/// - `toplevel`, the names that are replaced in scope, such as `derivation` or `__findFile`.
+///
+/// For example, to pass the names of the environment variables that are read to the primop:
+///
+/// ```nix
+/// record: {
+///   builtins.getEnv = name: builtins.getEnv (record name);
+/// }
+/// ```
+///
+/// This has some limitations, which apply to all modules that are built on it:
+///
+/// - Only code that is evaluated through the overrides, and the files it imports, is affected.
+///   Values that were created in other ways, such as functions passed in from Rust, use the original builtins.
+/// - Files imported this way are not shared with the evaluator's import cache, so they are
+///   parsed again for every import.
+/// - The primop is called when the overridden function is evaluated, so unevaluated parts of a
+///   value have not been seen yet.
+/// - The primop only receives the arguments of the call, not its source position or the
+///   attribute path that led to it.
+/// - The overrides are not a security boundary. Code can still reach the original builtins, for
+///   example as `__getEnv`, and builtins that are not overridden, such as fetchers or `builtins.path`,
+///   are not observed.
+/// - Errors returned by the primop can not be caught with `builtins.tryEval`.
+pub struct ScopedOverrides {
+    overrides: Value,
+    names: Vec<String>,
+}
+
+impl ScopedOverrides {
+    /// Calls the Nix function `hooks` with `primop`, to create the overrides.
+    pub fn new(eval_state: &mut EvalState, hooks: &str, primop: PrimOp) -> Result<Self> {
+        let primop = eval_state.new_value_primop(primop)?;
+        let f = eval_state.eval_from_string(OVERRIDES_EXPR, "<nix-bindings-expr overrides>")?;
+        let hooks = eval_state.eval_from_string(hooks, "<nix-bindings-expr overrides>")?;
+        let overrides = eval_state.call_multi(&f, &[hooks, primop])?;
+        let names = eval_state.require_attrs_names(&overrides)?;
+        Ok(ScopedOverrides { overrides, names })
+    }
+
+    /// Imports the Nix file at `path` with the overrides, using their `import`.
+    pub fn import(&self, eval_state: &mut EvalState, path: &str) -> Result<Value> {
+        let import = eval_state.require_attrs_select(&self.overrides, "import")?;
+        let path = eval_state.new_value_str(path)?;
+        eval_state.call(import, path)
+    }
+
+    /// Parses and evaluates `expr` with the overrides in scope, like [`EvalState::eval_from_string_with_scope`].
+    pub fn eval_from_string(
+        &self,
+        eval_state: &mut EvalState,
+        expr: &str,
+        path: &str,
+    ) -> Result<Value> {
+        let mut scope = Vec::new();
+        for name in &self.names {
+            scope.push((
+                name.clone(),
+                eval_state.require_attrs_select(&self.overrides, name)?,
+            ));
+        }
+        eval_state.eval_from_string_with_scope(expr, path, scope)
+    }
+}
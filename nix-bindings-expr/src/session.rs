@@ -0,0 +1,283 @@
+//! # Incremental Evaluation Sessions
+//!
+//! An [`EvalSession`] wraps an [`EvalState`] and keeps a growing scope of named
+//! values, similar to the variables of `nix repl`. Each expression evaluated in
+//! the session can refer to these names directly, so that interactive tools
+//! don't need to assemble a large `let` expression for every snippet.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::session::EvalSession;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let store = Store::open(None, HashMap::new())?;
+//! let mut session = EvalSession::new(EvalState::new(store, [])?);
+//!
+//! // Like `x = 1` in the repl
+//! session.eval_and_bind("x", "1", "<repl>")?;
+//!
+//! // Like `:a { y = 2; }` in the repl
+//! let attrs = session.eval("{ y = x + 1; }", "<repl>")?;
+//! session.bind_attrs(&attrs)?;
+//!
+//! let v = session.eval("x + y", "<repl>")?;
+//! assert_eq!(session.eval_state().require_int(&v)?, 3);
+//! assert_eq!(session.names(), vec!["x", "y"]);
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::eval_state::{check_scope_name, EvalState};
+use crate::value::Value;
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// Where a name in the session scope gets its value from.
+enum Binding {
+    /// A value that was bound directly.
+    Value(Value),
+    /// An attribute of one of the attribute sets added with [`EvalSession::bind_attrs`], by index.
+    Attr(usize),
+}
+
+/// An [`EvalState`] with a persistent scope of named values.
+///
+/// See the [module documentation](self) for an example.
+pub struct EvalSession {
+    eval_state: EvalState,
+    scope: BTreeMap<String, Binding>,
+    attrsets: Vec<Value>,
+}
+
+impl EvalSession {
+    /// Creates a new session with an empty scope.
+    pub fn new(eval_state: EvalState) -> Self {
+        EvalSession {
+            eval_state,
+            scope: BTreeMap::new(),
+            attrsets: Vec::new(),
+        }
+    }
+
+    /// Returns the [`EvalState`] that the session evaluates with.
+    ///
+    /// Use this for inspecting the values returned by the session.
+    pub fn eval_state(&mut self) -> &mut EvalState {
+        &mut self.eval_state
+    }
+
+    /// Binds `name` to `value`, replacing any previous binding of `name`.
+    ///
+    /// Returns an [`Err`] if `name` is not a valid Nix identifier.
+    #[doc(alias = "let")]
+    #[doc(alias = "insert")]
+    pub fn bind(&mut self, name: &str, value: Value) -> Result<()> {
+        check_scope_name(name)?;
+        self.scope.insert(name.to_owned(), Binding::Value(value));
+        Ok(())
+    }
+
+    /// Adds all attributes of an [attribute set][`crate::value::ValueType::AttrSet`] to the scope, like `:a` in `nix repl`.
+    ///
+    /// Forces the attribute set, but not its attributes. Existing bindings with the same names are replaced.
+    ///
+    /// Returns the names that were bound, or an [`Err`] without binding anything if any of the names is not a valid Nix identifier.
+    #[doc(alias = "merge")]
+    #[doc(alias = "add_attrs")]
+    pub fn bind_attrs(&mut self, attrs: &Value) -> Result<Vec<String>> {
+        let names = self.eval_state.require_attrs_names(attrs)?;
+        // Check all names first, so that we don't add only part of the attrset.
+        for name in &names {
+            check_scope_name(name)?;
+        }
+        let index = self.attrsets.len();
+        self.attrsets.push(attrs.clone());
+        for name in &names {
+            self.scope.insert(name.clone(), Binding::Attr(index));
+        }
+        Ok(names)
+    }
+
+    /// Removes `name` from the scope.
+    ///
+    /// Returns whether `name` was bound.
+    pub fn unbind(&mut self, name: &str) -> bool {
+        self.scope.remove(name).is_some()
+    }
+
+    /// Returns the bound names, in sorted order.
+    #[doc(alias = "list")]
+    #[doc(alias = "variables")]
+    pub fn names(&self) -> Vec<&str> {
+        self.scope.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Returns the value bound to `name`, or `Ok(None)` if `name` is not bound.
+    ///
+    /// Values that were added with [`bind_attrs`][`Self::bind_attrs`] are forced by this method.
+    pub fn lookup(&mut self, name: &str) -> Result<Option<Value>> {
+        match self.scope.get(name) {
+            None => Ok(None),
+            Some(Binding::Value(v)) => Ok(Some(v.clone())),
+            Some(Binding::Attr(index)) => {
+                let attrs = self.attrsets[*index].clone();
+                self.eval_state.require_attrs_select(&attrs, name).map(Some)
+            }
+        }
+    }
+
+    /// Parses and evaluates a Nix expression `expr` in which the bound names are in scope.
+    ///
+    /// See [`EvalState::eval_from_string_with_scope`] for details about how the names are bound.
+    #[doc(alias = "eval_from_string")]
+    pub fn eval(&mut self, expr: &str, path: &str) -> Result<Value> {
+        // Group the names by the attrset they are inherited from. Directly bound
+        // values are collected into an extra attrset, which goes last.
+        let mut values = Vec::new();
+        let mut inherits: Vec<Vec<&str>> = vec![Vec::new(); self.attrsets.len() + 1];
+        for (name, binding) in &self.scope {
+            match binding {
+                Binding::Value(v) => {
+                    values.push((name.clone(), v.clone()));
+                    inherits[self.attrsets.len()].push(name);
+                }
+                Binding::Attr(index) => inherits[*index].push(name),
+            }
+        }
+        let mut attrsets = self.attrsets.clone();
+        attrsets.push(self.eval_state.new_value_attrs(values)?);
+        let scopes: Vec<(Value, Vec<&str>)> = attrsets.into_iter().zip(inherits).collect();
+
+        self.eval_state
+            .eval_from_string_with_inherits(expr, path, &scopes)
+    }
+
+    /// Evaluates `expr` like [`eval`][`Self::eval`] and binds the result to `name`, like `name = expr` in `nix repl`.
+    ///
+    /// The name is not bound if evaluation fails.
+    pub fn eval_and_bind(&mut self, name: &str, expr: &str, path: &str) -> Result<Value> {
+        check_scope_name(name)?;
+        let v = self.eval(expr, path)?;
+        self.bind(name, v.clone())?;
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::gc_register_my_thread;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    fn new_session() -> EvalSession {
+        let store = Store::open(None, HashMap::new()).unwrap();
+        EvalSession::new(EvalState::new(store, []).unwrap())
+    }
+
+    #[test]
+    fn session_empty_scope() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        let v = session.eval("1 + 1", "<test>").unwrap();
+        assert_eq!(session.eval_state().require_int(&v).unwrap(), 2);
+        assert!(session.names().is_empty());
+    }
+
+    #[test]
+    fn session_bind_and_eval() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        let a = session.eval_state().new_value_int(40).unwrap();
+        session.bind("a", a).unwrap();
+        session.eval_and_bind("b", "a + 1", "<test>").unwrap();
+        let v = session.eval("a + b", "<test>").unwrap();
+        assert_eq!(session.eval_state().require_int(&v).unwrap(), 81);
+        assert_eq!(session.names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn session_rebind_shadows() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        session.eval_and_bind("x", "1", "<test>").unwrap();
+        session.eval_and_bind("x", "x + 10", "<test>").unwrap();
+        let v = session.eval("x", "<test>").unwrap();
+        assert_eq!(session.eval_state().require_int(&v).unwrap(), 11);
+    }
+
+    #[test]
+    fn session_shadows_builtins() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        session
+            .eval_and_bind("toString", "x: \"custom\"", "<test>")
+            .unwrap();
+        let v = session.eval("toString 1", "<test>").unwrap();
+        assert_eq!(session.eval_state().require_string(&v).unwrap(), "custom");
+    }
+
+    #[test]
+    fn session_bind_attrs_lazy() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        let attrs = session
+            .eval("{ a = 1; b = throw \"not forced\"; }", "<test>")
+            .unwrap();
+        let names = session.bind_attrs(&attrs).unwrap();
+        assert_eq!(names, vec!["a", "b"]);
+        let v = session.eval("a + 1", "<test>").unwrap();
+        assert_eq!(session.eval_state().require_int(&v).unwrap(), 2);
+        let r = session.eval("b", "<test>");
+        assert!(r.unwrap_err().to_string().contains("not forced"));
+    }
+
+    #[test]
+    fn session_bind_attrs_overrides() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        session.eval_and_bind("a", "1", "<test>").unwrap();
+        let attrs = session.eval("{ a = 2; }", "<test>").unwrap();
+        session.bind_attrs(&attrs).unwrap();
+        session.eval_and_bind("b", "a", "<test>").unwrap();
+        let b = session.lookup("b").unwrap().unwrap();
+        assert_eq!(session.eval_state().require_int(&b).unwrap(), 2);
+        let a = session.lookup("a").unwrap().unwrap();
+        assert_eq!(session.eval_state().require_int(&a).unwrap(), 2);
+    }
+
+    #[test]
+    fn session_unbind() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        session.eval_and_bind("a", "1", "<test>").unwrap();
+        assert!(session.unbind("a"));
+        assert!(!session.unbind("a"));
+        assert!(session.lookup("a").unwrap().is_none());
+        assert!(session.eval("a", "<test>").is_err());
+    }
+
+    #[test]
+    fn session_bind_invalid_name() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        let v = session.eval_state().new_value_int(1).unwrap();
+        assert!(session.bind("not valid", v.clone()).is_err());
+        assert!(session.bind("in", v.clone()).is_err());
+        session.bind("dashed-name'", v).unwrap();
+    }
+
+    #[test]
+    fn session_eval_error_keeps_scope() {
+        let _guard = gc_register_my_thread().unwrap();
+        let mut session = new_session();
+        assert!(session
+            .eval_and_bind("a", "throw \"no\"", "<test>")
+            .is_err());
+        assert!(session.names().is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+//! # Sharing an `EvalState` Between Threads
+//!
+//! [`EvalState`] and [`Value`] are not [`Send`], because the Nix evaluator is not
+//! thread safe, and because [primops][`crate::primop::PrimOp`] may hold data that
+//! must stay on one thread.
+//!
+//! A [`SharedEvalState`] keeps its `EvalState` on a dedicated, GC-registered owner thread,
+//! and is a [`Send`] + [`Sync`] handle that sends functions to that thread and waits for
+//! their results. Values that need to cross threads are wrapped in a [`SharedValue`].
+//!
+//! Requires Nix 2.26.0 or later.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalStateBuilder, test_init};
+//! # use nix_bindings_expr::shared::SharedEvalState;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! let shared = SharedEvalState::new(|| EvalStateBuilder::new(Store::open(None, HashMap::new())?))?;
+//!
+//! let f = shared.eval_from_string("x: x * 2", "<example>")?;
+//! let handles: Vec<_> = (0..4)
+//!     .map(|i| {
+//!         let shared = shared.clone();
+//!         let f = f.clone();
+//!         std::thread::spawn(move || {
+//!             shared.with_value(&f, move |es, f| {
+//!                 let x = es.new_value_int(i)?;
+//!                 let y = es.call(f.clone(), x)?;
+//!                 es.require_int(&y)
+//!             })
+//!         })
+//!     })
+//!     .collect();
+//! for (i, h) in handles.into_iter().enumerate() {
+//!     assert_eq!(h.join().unwrap()?, 2 * i as i64);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::eval_state::{gc_register_my_thread, EvalState, EvalStateBuilder};
+use crate::pool::EvalStatePool;
+use crate::value::Value;
+use anyhow::{bail, Result};
+use std::mem::ManuallyDrop;
+use std::sync::Arc;
+
+/// A [`Send`] + [`Sync`] handle to an [`EvalState`] that lives on its own thread.
+///
+/// Cloning the handle is cheap and refers to the same `EvalState`. Calls from
+/// different threads are run one at a time on the owner thread, so this does not make
+/// evaluation parallel; use an [`EvalStatePool`] for that.
+///
+/// The `EvalState` is dropped on the owner thread, after the last handle and [`SharedValue`] are dropped.
+///
+/// See the [module documentation](self) for an example.
+#[derive(Clone)]
+pub struct SharedEvalState {
+    owner: Arc<EvalStatePool>,
+}
+
+impl SharedEvalState {
+    /// Starts the owner thread, which builds its [`EvalState`] from the [`EvalStateBuilder`] returned by `builder`.
+    ///
+    /// Returns an [`Err`] if the `EvalState` can not be built.
+    pub fn new<F>(builder: F) -> Result<Self>
+    where
+        F: Fn() -> Result<EvalStateBuilder> + Send + Sync + 'static,
+    {
+        Ok(SharedEvalState {
+            owner: Arc::new(EvalStatePool::new(1, 0, builder)?),
+        })
+    }
+
+    /// Runs `f` with exclusive access to the [`EvalState`], on the owner thread.
+    ///
+    /// Blocks until `f` has run. Values can not be returned directly; wrap them with [`share`][`Self::share`] instead.
+    ///
+    /// Returns an [`Err`] if `f` panics, or if it is called from within another call on the same `SharedEvalState`,
+    /// which would otherwise deadlock.
+    #[doc(alias = "lock")]
+    pub fn with<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut EvalState) -> Result<R> + Send + 'static,
+    {
+        if self.owner.is_worker_thread() {
+            bail!(
+                "SharedEvalState: can not be used from within a call on the same SharedEvalState"
+            );
+        }
+        self.owner.submit_fn(f)?.wait()
+    }
+
+    /// Runs `f` with exclusive access to the [`EvalState`] and the [`Value`] inside `value`, on the owner thread.
+    ///
+    /// Returns an [`Err`] if `value` belongs to a different `SharedEvalState`.
+    pub fn with_value<R, F>(&self, value: &SharedValue, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut EvalState, &Value) -> Result<R> + Send + 'static,
+    {
+        if !Arc::ptr_eq(&self.owner, &value.owner) {
+            bail!("SharedEvalState: the value belongs to a different SharedEvalState");
+        }
+        let value = value.clone();
+        self.with(move |es| f(es, &value.inner.value))
+    }
+
+    /// Wraps a [`Value`] of this `SharedEvalState` so that it can be moved to other threads.
+    ///
+    /// Returns an [`Err`] if it is not called within [`with`][`Self::with`], because values
+    /// created elsewhere belong to a different [`EvalState`].
+    pub fn share(&self, value: Value) -> Result<SharedValue> {
+        if !self.owner.is_worker_thread() {
+            bail!("SharedEvalState::share: can only share values within a call on the same SharedEvalState");
+        }
+        Ok(SharedValue {
+            inner: Arc::new(SharedValueInner {
+                value: ManuallyDrop::new(value),
+            }),
+            owner: self.owner.clone(),
+        })
+    }
+
+    /// Parses and evaluates `expr`, like [`EvalState::eval_from_string`].
+    pub fn eval_from_string(&self, expr: &str, path: &str) -> Result<SharedValue> {
+        let (expr, path) = (expr.to_owned(), path.to_owned());
+        let shared = self.clone();
+        self.with(move |es| {
+            let v = es.eval_from_string(&expr, &path)?;
+            shared.share(v)
+        })
+    }
+}
+
+/// A [`Value`] that can be moved between threads.
+///
+/// Created by [`SharedEvalState::share`], and used with [`SharedEvalState::with_value`].
+/// Cloning a `SharedValue` does not touch the [`Value`], and does not wait for the owner thread.
+#[derive(Clone)]
+pub struct SharedValue {
+    // Dropped before `owner`, so that the value is released before its EvalState.
+    inner: Arc<SharedValueInner>,
+    owner: Arc<EvalStatePool>,
+}
+
+/// The [`Value`] of a [`SharedValue`] and its clones.
+struct SharedValueInner {
+    value: ManuallyDrop<Value>,
+}
+/// The value is only used on the owner thread, within [`SharedEvalState::with_value`].
+/// On other threads, only its reference count is decremented when it is dropped, which
+/// the Nix C API allows from any thread that is registered with the garbage collector.
+unsafe impl Send for SharedValueInner {}
+unsafe impl Sync for SharedValueInner {}
+
+impl Drop for SharedValueInner {
+    fn drop(&mut self) {
+        // Releasing the value on an unregistered thread is not safe, so it is leaked
+        // if the garbage collector can not register the thread.
+        if let Ok(guard) = gc_register_my_thread() {
+            // Safety: the value is not used after this.
+            unsafe { ManuallyDrop::drop(&mut self.value) };
+            drop(guard);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn new_shared() -> SharedEvalState {
+        SharedEvalState::new(|| EvalStateBuilder::new(Store::open(None, HashMap::new())?)).unwrap()
+    }
+
+    #[test]
+    fn shared_is_send_sync() {
+        assert_send_sync::<SharedEvalState>();
+        assert_send_sync::<SharedValue>();
+    }
+
+    #[test]
+    fn shared_eval_from_threads() {
+        let shared = new_shared();
+        let list = shared
+            .eval_from_string("builtins.genList (x: x * x) 10", "<test>")
+            .unwrap();
+        std::thread::scope(|s| {
+            for i in 0..8u32 {
+                let shared = &shared;
+                let list = &list;
+                s.spawn(move || {
+                    let r = shared
+                        .with_value(list, move |es, list| {
+                            let v = es.require_list_select_idx_strict(list, i)?.unwrap();
+                            es.require_int(&v)
+                        })
+                        .unwrap();
+                    assert_eq!(r, (i * i) as i64);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn shared_value_moves_between_threads() {
+        let shared = new_shared();
+        let v = {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.eval_from_string("{ a = \"hi\"; }", "<test>"))
+                .join()
+                .unwrap()
+                .unwrap()
+        };
+        let v2 = v.clone();
+        // Dropped on a thread that is not registered with the garbage collector.
+        std::thread::spawn(move || drop(v)).join().unwrap();
+        let s = shared
+            .with_value(&v2, |es, v| {
+                let a = es.require_attrs_select(v, "a")?;
+                es.require_string(&a)
+            })
+            .unwrap();
+        assert_eq!(s, "hi");
+    }
+
+    #[test]
+    fn shared_value_outlives_handle() {
+        let shared = new_shared();
+        let v = shared.eval_from_string("[ 1 2 ]", "<test>").unwrap();
+        let shared2 = shared.clone();
+        drop(shared);
+        let n = shared2
+            .with_value(&v, |es, v| es.require_list_size(v))
+            .unwrap();
+        assert_eq!(n, 2);
+        drop(shared2);
+        // The value keeps the EvalState alive until it is dropped.
+        std::thread::spawn(move || drop(v)).join().unwrap();
+    }
+
+    #[test]
+    fn shared_value_other_state() {
+        let a = new_shared();
+        let b = new_shared();
+        let v = a.eval_from_string("1", "<test>").unwrap();
+        let r = b.with_value(&v, |es, v| es.require_int(v));
+        assert!(r.unwrap_err().to_string().contains("different"));
+    }
+
+    #[test]
+    fn shared_share_outside_owner_thread() {
+        let shared = new_shared();
+        gc_registering_current_thread(|| {
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let v = es.new_value_int(1).unwrap();
+            let r = shared.share(v);
+            assert!(r.unwrap_err().to_string().contains("within a call"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn shared_nested_call() {
+        let shared = new_shared();
+        let inner = shared.clone();
+        let r = shared
+            .with(move |_| Ok(inner.with(|_| Ok(())).is_err()))
+            .unwrap();
+        assert!(r);
+    }
+
+    #[test]
+    fn shared_panic() {
+        let shared = new_shared();
+        let shared2 = shared.clone();
+        let r = std::thread::spawn(move || shared2.with(|_| -> Result<()> { panic!("oops") }))
+            .join()
+            .unwrap();
+        assert!(r.is_err());
+        // The EvalState remains usable.
+        let v = shared.eval_from_string("1 + 1", "<test>").unwrap();
+        assert_eq!(shared.with_value(&v, |es, v| es.require_int(v)).unwrap(), 2);
+    }
+}
@@ -0,0 +1,174 @@
+//! # Threads That Are Registered with the Garbage Collector
+//!
+//! Every thread that touches Nix values must be registered with the Boehm garbage
+//! collector, or the collector may free memory that is still in use. The functions in
+//! this module spawn threads that are registered for their whole lifetime, so that
+//! [`gc_register_my_thread`] does not have to be called by hand.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init};
+//! # use nix_bindings_expr::thread::spawn_registered;
+//! # use nix_bindings_store::store::Store;
+//! # use std::collections::HashMap;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! let handle = spawn_registered(|| -> anyhow::Result<i64> {
+//!     let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+//!     let v = es.eval_from_string("1 + 1", "<example>")?;
+//!     es.require_int(&v)
+//! });
+//! assert_eq!(handle.join().unwrap()??, 2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `rayon` feature, [`rayon_thread_pool_builder`] returns a
+//! `rayon::ThreadPoolBuilder` whose threads are registered.
+//!
+//! In debug builds, creating, cloning or dropping a [`Value`][`crate::value::Value`] on an unregistered thread panics.
+
+use crate::eval_state::gc_register_my_thread;
+#[cfg(feature = "rayon")]
+use crate::eval_state::ThreadRegistrationGuard;
+use anyhow::Result;
+use std::thread::{JoinHandle, Scope, ScopedJoinHandle};
+
+/// Spawns a thread that is registered with the garbage collector while it runs `f`.
+///
+/// The thread's result is an [`Err`] if the registration failed, in which case `f` is not run.
+#[doc(alias = "spawn")]
+#[doc(alias = "thread_spawn")]
+pub fn spawn_registered<F, T>(f: F) -> JoinHandle<Result<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    std::thread::spawn(move || run_registered(f))
+}
+
+/// Like [`spawn_registered`], for a scoped thread created with [`std::thread::scope`].
+#[doc(alias = "scope")]
+pub fn spawn_scoped_registered<'scope, 'env, F, T>(
+    scope: &'scope Scope<'scope, 'env>,
+    f: F,
+) -> ScopedJoinHandle<'scope, Result<T>>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    scope.spawn(move || run_registered(f))
+}
+
+fn run_registered<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T,
+{
+    let guard = gc_register_my_thread()?;
+    let r = f();
+    drop(guard);
+    Ok(r)
+}
+
+#[cfg(feature = "rayon")]
+thread_local! {
+    /// The registration of a rayon worker thread, from its start handler until its exit handler.
+    static RAYON_GUARD: std::cell::RefCell<Option<ThreadRegistrationGuard>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Returns a [`rayon::ThreadPoolBuilder`] whose threads register with the garbage collector when they start,
+/// and unregister when they exit.
+///
+/// Requires the `rayon` feature.
+///
+/// Note that rayon's global pool is only configurable before its first use; see [`rayon::ThreadPoolBuilder::build_global`].
+///
+/// A thread that fails to register panics in its start handler. The panic is passed to the pool's
+/// [`panic_handler`][`rayon::ThreadPoolBuilder::panic_handler`], which aborts the process by default.
+#[cfg(feature = "rayon")]
+pub fn rayon_thread_pool_builder() -> rayon::ThreadPoolBuilder {
+    rayon::ThreadPoolBuilder::new()
+        .start_handler(|_| {
+            let guard = gc_register_my_thread()
+                .expect("could not register rayon thread with the garbage collector");
+            RAYON_GUARD.with(|g| *g.borrow_mut() = Some(guard));
+        })
+        .exit_handler(|_| {
+            RAYON_GUARD.with(|g| drop(g.borrow_mut().take()));
+        })
+}
+
+/// Panics in debug builds if the current thread is not registered with the garbage collector.
+#[inline]
+pub(crate) fn debug_assert_thread_registered() {
+    #[cfg(debug_assertions)]
+    {
+        let registered = unsafe { nix_bindings_bdwgc_sys::GC_thread_is_registered() };
+        assert!(
+            registered != 0,
+            "a Nix value was used on a thread that is not registered with the garbage collector; use `gc_register_my_thread` or `nix_bindings_expr::thread::spawn_registered`"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::EvalState;
+    use nix_bindings_store::store::Store;
+    use std::collections::HashMap;
+
+    fn eval_int(expr: &str) -> i64 {
+        let store = Store::open(None, HashMap::new()).unwrap();
+        let mut es = EvalState::new(store, []).unwrap();
+        let v = es.eval_from_string(expr, "<test>").unwrap();
+        es.require_int(&v).unwrap()
+    }
+
+    #[test]
+    fn spawn_registered_evaluates() {
+        let r = spawn_registered(|| eval_int("20 + 22")).join().unwrap();
+        assert_eq!(r.unwrap(), 42);
+    }
+
+    #[test]
+    fn spawn_scoped_registered_evaluates() {
+        let exprs = ["1", "2 * 2", "3 * 3"];
+        let results: Vec<i64> = std::thread::scope(|s| {
+            let handles: Vec<_> = exprs
+                .iter()
+                .map(|expr| spawn_scoped_registered(s, || eval_int(expr)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap().unwrap())
+                .collect()
+        });
+        assert_eq!(results, vec![1, 4, 9]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn unregistered_thread_panics() {
+        let r = std::thread::spawn(debug_assert_thread_registered).join();
+        assert!(r.is_err());
+        spawn_registered(debug_assert_thread_registered)
+            .join()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn rayon_pool_evaluates() {
+        use rayon::prelude::*;
+        let pool = rayon_thread_pool_builder().num_threads(3).build().unwrap();
+        let results: Vec<i64> = pool.install(|| {
+            (0..6)
+                .into_par_iter()
+                .map(|i| eval_int(&format!("{i} * 10")))
+                .collect()
+        });
+        assert_eq!(results, vec![0, 10, 20, 30, 40, 50]);
+    }
+}
@@ -0,0 +1,202 @@
+//! # Capturing Traces and Warnings
+//!
+//! A [`TraceCapture`] passes the messages of `builtins.trace` and `builtins.warn` to a Rust
+//! function, instead of letting Nix print them to standard error. This includes `lib.trace`,
+//! `lib.warn` and the other Nixpkgs functions that are built on them.
+//!
+//! ```rust
+//! # use nix_bindings_expr::eval_state::{EvalState, test_init, gc_register_my_thread};
+//! # use nix_bindings_expr::trace::{TraceCapture, TraceEvent, TraceKind};
+//! # use nix_bindings_store::store::Store;
+//! # use std::cell::RefCell;
+//! # use std::collections::HashMap;
+//! # use std::rc::Rc;
+//! # fn example() -> anyhow::Result<()> {
+//! # test_init();
+//! # let guard = gc_register_my_thread()?;
+//! let mut es = EvalState::new(Store::open(None, HashMap::new())?, [])?;
+//! let events = Rc::new(RefCell::new(Vec::new()));
+//! let sink = events.clone();
+//! let capture = TraceCapture::new(&mut es, move |event| sink.borrow_mut().push(event))?;
+//! let v = capture.eval_from_string(&mut es, r#"builtins.trace "hello" 1"#, "<example>")?;
+//! assert_eq!(es.require_int(&v)?, 1);
+//! assert_eq!(
+//!     *events.borrow(),
+//!     vec![TraceEvent { kind: TraceKind::Trace, message: "hello".to_string() }]
+//! );
+//! # drop(guard);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## How it works
+//!
+//! The capture evaluates code with [`ScopedOverrides`], overriding `trace` and `warn`. See
+//! [`ScopedOverrides`] for the limitations. In addition:
+//!
+//! - Events only carry the message. To relate events to attributes, force one attribute at a
+//!   time and collect the events in between.
+//! - Warnings that the evaluator itself emits, such as deprecation warnings about the language,
+//!   and `builtins.traceVerbose`, still go to standard error.
+//! - `abort-on-warn` is not applied to captured warnings.
+
+use crate::eval_state::EvalState;
+use crate::primop::{PrimOp, PrimOpMeta};
+use crate::print::PrintOptions;
+use crate::scoped::ScopedOverrides;
+use crate::value::ValueType;
+use anyhow::{bail, Result};
+use cstr::cstr;
+use std::ops::Deref;
+
+/// The function that produced a [`TraceEvent`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TraceKind {
+    /// `builtins.trace`.
+    Trace,
+    /// `builtins.warn`.
+    Warning,
+}
+
+/// A message that evaluated code traced or warned about.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceEvent {
+    /// Which function produced the message.
+    pub kind: TraceKind,
+    /// The message. Non-string `trace` messages are printed as Nix values, without forcing their contents.
+    pub message: String,
+}
+
+/// The hooks for [`ScopedOverrides`], given the capturing primop.
+///
+/// `capture` returns `null`, so that `seq` can sequence it before the returned value.
+const HOOKS_EXPR: &str = r#"
+capture: {
+  builtins = {
+    trace = msg: v: builtins.seq (capture "trace" msg) v;
+    warn = msg: v:
+      if builtins.isString msg
+      then builtins.seq (capture "warning" msg) v
+      else throw "builtins.warn: the first argument must be a string";
+  };
+}
+"#;
+
+/// Passes the traces and warnings of the code that it evaluates to a Rust function.
+///
+/// See the [module documentation](self) for an example and limitations.
+pub struct TraceCapture {
+    overrides: ScopedOverrides,
+}
+
+impl TraceCapture {
+    /// Creates a capture for evaluating with `eval_state`, which calls `on_event` for each trace and warning.
+    pub fn new(
+        eval_state: &mut EvalState,
+        on_event: impl Fn(TraceEvent) + 'static,
+    ) -> Result<Self> {
+        let primop = PrimOp::new(
+            eval_state,
+            PrimOpMeta {
+                name: cstr!("captureTrace"),
+                doc: cstr!(
+                    "Passes a trace or warning message to the host program, and returns null."
+                ),
+                args: [cstr!("kind"), cstr!("message")],
+            },
+            Box::new(move |es, [kind, message]| {
+                let kind = match es.require_string(kind)?.as_str() {
+                    "trace" => TraceKind::Trace,
+                    "warning" => TraceKind::Warning,
+                    kind => bail!("captureTrace: unknown kind `{}`", kind),
+                };
+                let message = if es.value_type(message)? == ValueType::String {
+                    es.require_string(message)?
+                } else {
+                    let options = PrintOptions {
+                        force: false,
+                        ..PrintOptions::default()
+                    };
+                    es.print_value(message, &options)?
+                };
+                on_event(TraceEvent { kind, message });
+                es.builtin("null")
+            }),
+        )?;
+        let overrides = ScopedOverrides::new(eval_state, HOOKS_EXPR, primop)?;
+        Ok(TraceCapture { overrides })
+    }
+}
+
+/// Provides [`ScopedOverrides::import`] and [`ScopedOverrides::eval_from_string`] for evaluating with capturing.
+impl Deref for TraceCapture {
+    type Target = ScopedOverrides;
+
+    fn deref(&self) -> &ScopedOverrides {
+        &self.overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::tests::gc_registering_current_thread;
+    use nix_bindings_store::store::Store;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn trace_and_warn_in_imported_file() {
+        gc_registering_current_thread(|| {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(
+                dir.path().join("default.nix"),
+                r#"{ a = builtins.trace 42 1; b = import ./b.nix; }"#,
+            )
+            .unwrap();
+            std::fs::write(
+                dir.path().join("b.nix"),
+                r#"builtins.warn "deprecated" (builtins.trace "inner" 2)"#,
+            )
+            .unwrap();
+
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let sink = events.clone();
+            let mut es = EvalState::new(Store::open(None, HashMap::new()).unwrap(), []).unwrap();
+            let capture =
+                TraceCapture::new(&mut es, move |event| sink.borrow_mut().push(event)).unwrap();
+            let v = capture
+                .import(&mut es, dir.path().join("default.nix").to_str().unwrap())
+                .unwrap();
+            assert!(events.borrow().is_empty());
+
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            assert_eq!(es.require_int(&a).unwrap(), 1);
+            assert_eq!(
+                events.borrow_mut().drain(..).collect::<Vec<_>>(),
+                vec![TraceEvent {
+                    kind: TraceKind::Trace,
+                    message: "42".to_string(),
+                }]
+            );
+
+            let b = es.require_attrs_select(&v, "b").unwrap();
+            assert_eq!(es.require_int(&b).unwrap(), 2);
+            assert_eq!(
+                events.borrow_mut().drain(..).collect::<Vec<_>>(),
+                vec![
+                    TraceEvent {
+                        kind: TraceKind::Warning,
+                        message: "deprecated".to_string(),
+                    },
+                    TraceEvent {
+                        kind: TraceKind::Trace,
+                        message: "inner".to_string(),
+                    },
+                ]
+            );
+        })
+        .unwrap();
+    }
+}
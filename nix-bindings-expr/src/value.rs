@@ -1,7 +1,11 @@
 pub mod __private;
 
 use nix_bindings_expr_sys as raw;
-use nix_bindings_util::{check_call, context::Context};
+use nix_bindings_util::string_return::{
+    callback_get_result_string, callback_get_result_string_data,
+};
+use nix_bindings_util::{check_call, context::Context, result_string_init};
+use std::ffi::CStr;
 use std::ptr::{null_mut, NonNull};
 
 // TODO: test: cloning a thunk does not duplicate the evaluation.
@@ -92,6 +96,7 @@ impl Value {
     ///
     /// The caller must ensure that the provided `inner` has a positive reference count, and that `inner` is not used after the returned `Value` is dropped.
     pub(crate) unsafe fn new(inner: *mut raw::Value) -> Self {
+        crate::thread::debug_assert_thread_registered();
         Value {
             inner: NonNull::new(inner).unwrap(),
         }
@@ -119,6 +124,10 @@ impl Value {
 }
 impl Drop for Value {
     fn drop(&mut self) {
+        // Panicking while unwinding would abort the process.
+        if !std::thread::panicking() {
+            crate::thread::debug_assert_thread_registered();
+        }
         unsafe {
             // ignoring error because the only failure mode is leaking memory
             raw::value_decref(null_mut(), self.inner.as_ptr());
@@ -127,6 +136,7 @@ impl Drop for Value {
 }
 impl Clone for Value {
     fn clone(&self) -> Self {
+        crate::thread::debug_assert_thread_registered();
         // TODO: Is it worth allocating a new Context here? Ideally cloning is cheap.
         //       this is very unlikely to error, and it is not recoverable
         //       Maybe try without, and try again with context to report details?
@@ -138,4 +148,66 @@ impl Clone for Value {
     }
 }
 
+/// Shows the type of the value, and the contents of scalars such as integers and strings.
+///
+/// This does not [force][`crate::eval_state::EvalState::force`] the value, so unevaluated values are shown as `Value(Thunk)`.
+/// For printing evaluated attribute sets and lists, see [`EvalState::display`][`crate::eval_state::EvalState::display`].
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut context = Context::new();
+        let ptr = self.inner.as_ptr();
+        let t = unsafe { check_call!(raw::get_type(&mut context, ptr)) };
+        let mut t_fmt = f.debug_tuple("Value");
+        match t.map(ValueType::from_raw) {
+            Err(_) => t_fmt.field(&ValueType::Unknown),
+            Ok(None) => t_fmt.field(&format_args!("Thunk")),
+            Ok(Some(ValueType::Int)) => {
+                match unsafe { check_call!(raw::get_int(&mut context, ptr)) } {
+                    Ok(i) => t_fmt.field(&format_args!("Int({})", i)),
+                    Err(_) => t_fmt.field(&ValueType::Int),
+                }
+            }
+            Ok(Some(ValueType::Bool)) => {
+                match unsafe { check_call!(raw::get_bool(&mut context, ptr)) } {
+                    Ok(b) => t_fmt.field(&format_args!("Bool({})", b)),
+                    Err(_) => t_fmt.field(&ValueType::Bool),
+                }
+            }
+            Ok(Some(ValueType::Float)) => {
+                match unsafe { check_call!(raw::get_float(&mut context, ptr)) } {
+                    Ok(x) => t_fmt.field(&format_args!("Float({:?})", x)),
+                    Err(_) => t_fmt.field(&ValueType::Float),
+                }
+            }
+            Ok(Some(ValueType::String)) => {
+                let mut r = result_string_init!();
+                let r = unsafe {
+                    check_call!(raw::get_string(
+                        &mut context,
+                        ptr,
+                        Some(callback_get_result_string),
+                        callback_get_result_string_data(&mut r)
+                    ))
+                }
+                .and(r);
+                match r {
+                    Ok(s) => t_fmt.field(&format_args!("String({:?})", s)),
+                    Err(_) => t_fmt.field(&ValueType::String),
+                }
+            }
+            Ok(Some(ValueType::Path)) => {
+                match unsafe { check_call!(raw::get_path_string(&mut context, ptr)) } {
+                    Ok(p) if !p.is_null() => {
+                        let p = unsafe { CStr::from_ptr(p) }.to_string_lossy();
+                        t_fmt.field(&format_args!("Path({:?})", p))
+                    }
+                    _ => t_fmt.field(&ValueType::Path),
+                }
+            }
+            Ok(Some(t)) => t_fmt.field(&t),
+        };
+        t_fmt.finish()
+    }
+}
+
 // Tested in eval_state.rs
@@ -1,3 +1,5 @@
+use crate::result_string_init;
+use crate::string_return::{callback_get_result_string, callback_get_result_string_data};
 use anyhow::{bail, Result};
 use nix_bindings_util_sys as raw;
 use std::ptr::null_mut;
@@ -51,6 +53,27 @@ impl Context {
         Ok(())
     }
 
+    /// Returns the name of the C++ exception type of the current error, such as `nix::ThrowError`.
+    ///
+    /// Returns [`None`] if there is no error, or if it is not a Nix error.
+    pub fn err_name(&self) -> Option<String> {
+        if unsafe { raw::err_code(self.inner.as_ptr()) } != raw::err_NIX_ERR_NIX_ERROR {
+            return None;
+        }
+        let mut ctx = Context::new();
+        let mut r = result_string_init!();
+        unsafe {
+            raw::err_name(
+                ctx.ptr(),
+                self.inner.as_ptr(),
+                Some(callback_get_result_string),
+                callback_get_result_string_data(&mut r),
+            );
+        }
+        ctx.check_err().ok()?;
+        r.ok()
+    }
+
     pub fn clear(&mut self) {
         unsafe {
             raw::set_err_msg(self.inner.as_ptr(), raw::err_NIX_OK, c"".as_ptr());
@@ -156,4 +179,12 @@ mod tests {
         assert!(r.is_err());
         assert_eq!(r.unwrap_err().to_string(), "dummy error message");
     }
+
+    #[test]
+    fn err_name_not_nix_error() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.err_name(), None);
+        set_dummy_err(ctx.ptr());
+        assert_eq!(ctx.err_name(), None);
+    }
 }